@@ -0,0 +1,276 @@
+use crate::{
+    rsa_generator::{self, Issuer},
+    secret::RsaSecret,
+    settings::{Ca, Settings},
+};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use kube::client::APIClient;
+
+const CERTIFICATE_FIELD: &str = "tls.crt";
+const KEY_FIELD: &str = "tls.key";
+/// Root replaced by rollover, kept in bundle until it expires
+const PREVIOUS_FIELD: &str = "previous.crt";
+/// Root staged by rollover, published in bundle before it signs anything
+const NEXT_CERTIFICATE_FIELD: &str = "next.crt";
+const NEXT_KEY_FIELD: &str = "next.key";
+
+/// Step made by CA rollover
+#[derive(Debug, PartialEq)]
+pub enum Rollover {
+    Unchanged,
+    /// Bundle changed and must be published, service certificates stay valid
+    Bundle,
+    /// Staged root became the issuer, service certificates must be reissued
+    Issuer,
+}
+
+/// Certificate authority kept in secret of operator namespace
+#[derive(Clone)]
+pub struct Authority {
+    client: APIClient,
+    config: Ca,
+    /// Annotation marking CA generated by operator, imported ones are never rolled over
+    generated_annotation: String,
+    /// Annotation with time when bundle with staged root was published
+    published_annotation: String,
+}
+
+impl Authority {
+    pub fn new(client: APIClient, config: Ca, settings: &Settings) -> Self {
+        Authority {
+            client,
+            config,
+            generated_annotation: settings.annotation_key("ca-generated"),
+            published_annotation: settings.annotation_key("ca-next-published-at"),
+        }
+    }
+
+    /// Current CA, generated when its secret is missing
+    ///
+    /// Existing secret with `tls.crt` and `tls.key` is imported as is. Only a confirmed
+    /// missing secret is bootstrapped, concurrent bootstraps are resolved by create conflict.
+    pub async fn issuer(&self) -> Result<Issuer> {
+        if let Some(issuer) = self.current().await? {
+            return Ok(issuer);
+        }
+
+        info!(
+            "Generate CA in secret {}/{}",
+            self.config.namespace, self.config.secret_name
+        );
+        let issuer = self.generate()?;
+        let mut secret = self.secret().await?;
+        secret
+            .add_field(CERTIFICATE_FIELD, issuer.certificate_pem()?)
+            .await?
+            .add_field(KEY_FIELD, issuer.key_pem()?)
+            .await?
+            .annotate(&self.generated_annotation, "true")
+            .await?;
+        if secret.create_exclusive().await? {
+            return Ok(issuer);
+        }
+
+        info!("CA was created concurrently... use it");
+        self.current().await?.ok_or_else(|| {
+            anyhow::format_err!(
+                "CA secret {}/{} disappeared during bootstrap",
+                self.config.namespace,
+                self.config.secret_name
+            )
+        })
+    }
+
+    /// CA from existing secret, `None` if secret is missing
+    async fn current(&self) -> Result<Option<Issuer>> {
+        let secret = match self.secret().await?.fetch().await? {
+            Some(secret) => secret,
+            None => return Ok(None),
+        };
+        match (
+            secret.data.get(CERTIFICATE_FIELD),
+            secret.data.get(KEY_FIELD),
+        ) {
+            (Some(certificate), Some(key)) => Ok(Some(Issuer::from_pem(&certificate.0, &key.0)?)),
+            // Never overwrite secret which may hold somebody else's CA
+            _ => Err(anyhow::format_err!(
+                "CA secret {}/{} has no {} and {}, fill or remove it",
+                self.config.namespace,
+                self.config.secret_name,
+                CERTIFICATE_FIELD,
+                KEY_FIELD
+            )),
+        }
+    }
+
+    /// Advance rollover of CA expiring within `rollover_days` and drop expired previous root
+    ///
+    /// New root is staged next to the current one and becomes the issuer only
+    /// `propagation_delay` seconds after the bundle with both roots was published
+    pub async fn rollover(&self) -> Result<Rollover> {
+        let current = self.issuer().await?;
+        let secret = self.secret().await?.get().await?;
+        let field = |name: &str| secret.data.get(name).map(|value| value.0.clone());
+
+        if let (Some(certificate), Some(key)) =
+            (field(NEXT_CERTIFICATE_FIELD), field(NEXT_KEY_FIELD))
+        {
+            let published_at = secret.metadata.annotations.get(&self.published_annotation);
+            let step = staged_step(published_at, self.config.propagation_delay, Utc::now());
+            if step == Rollover::Issuer {
+                info!("New CA root propagated... sign with it");
+                self.promote(&current, &certificate, &key).await?;
+            }
+            return Ok(step);
+        }
+
+        if current.expires_within(self.config.rollover_days)? {
+            if secret.metadata.annotations.get(&self.generated_annotation) == Some(&"true".into()) {
+                info!(
+                    "CA in secret {}/{} expires soon... stage new root",
+                    self.config.namespace, self.config.secret_name
+                );
+                let issuer = self.generate()?;
+                self.secret()
+                    .await?
+                    .add_field(NEXT_CERTIFICATE_FIELD, issuer.certificate_pem()?)
+                    .await?
+                    .add_field(NEXT_KEY_FIELD, issuer.key_pem()?)
+                    .await?
+                    .update()
+                    .await?;
+                return Ok(Rollover::Bundle);
+            }
+            warn!(
+                "Imported CA in secret {}/{} expires soon, replace it manually",
+                self.config.namespace, self.config.secret_name
+            );
+        }
+
+        match field(PREVIOUS_FIELD) {
+            Some(previous) if rsa_generator::is_expired(&previous)? => {
+                info!("Previous CA expired... remove it from bundle");
+                self.secret()
+                    .await?
+                    .clean(vec![PREVIOUS_FIELD.into()])
+                    .await?;
+                Ok(Rollover::Bundle)
+            }
+            _ => Ok(Rollover::Unchanged),
+        }
+    }
+
+    /// Record that bundle with staged root was published, it starts propagation delay
+    pub async fn published(&self) -> Result<()> {
+        let secret = self.secret().await?.get().await?;
+        if !secret.data.contains_key(NEXT_CERTIFICATE_FIELD)
+            || secret
+                .metadata
+                .annotations
+                .contains_key(&self.published_annotation)
+        {
+            return Ok(());
+        }
+        self.secret()
+            .await?
+            .annotate(&self.published_annotation, &Utc::now().to_rfc3339())
+            .await?
+            .update()
+            .await?;
+        Ok(())
+    }
+
+    /// PEM bundle of current, staged and previous roots, services trust all of them during rollover
+    pub async fn bundle(&self) -> Result<Vec<u8>> {
+        let mut bundle = self.issuer().await?.certificate_pem()?;
+        let secret = self.secret().await?;
+        for name in &[NEXT_CERTIFICATE_FIELD, PREVIOUS_FIELD] {
+            if let Some(root) = secret.field(name).await? {
+                bundle.extend(root);
+            }
+        }
+        Ok(bundle)
+    }
+
+    fn generate(&self) -> Result<Issuer> {
+        Ok(Issuer::generate(
+            self.config.key_type,
+            self.config.bits,
+            &self.config.common_name,
+            self.config.validity_days,
+        )?)
+    }
+
+    /// Make staged root the issuer, current root is kept in bundle as previous
+    async fn promote(&self, current: &Issuer, certificate: &[u8], key: &[u8]) -> Result<()> {
+        // Promotion interrupted after the new root was saved
+        if current.certificate_pem()? != certificate {
+            self.secret()
+                .await?
+                .add_field(CERTIFICATE_FIELD, certificate)
+                .await?
+                .add_field(KEY_FIELD, key)
+                .await?
+                .add_field(PREVIOUS_FIELD, current.certificate_pem()?)
+                .await?
+                .update()
+                .await?;
+        }
+        let secret = self.secret().await?;
+        secret
+            .clean(vec![NEXT_CERTIFICATE_FIELD.into(), NEXT_KEY_FIELD.into()])
+            .await?;
+        secret.remove_annotation(&self.published_annotation).await?;
+        Ok(())
+    }
+
+    async fn secret(&self) -> Result<RsaSecret> {
+        RsaSecret::new(
+            self.client.clone(),
+            self.config.secret_name.clone(),
+            Some(self.config.namespace.clone()),
+        )
+        .await
+    }
+}
+
+/// Rollover step for root staged and published at `published_at`
+///
+/// Bundle is published again when recording its publication failed
+fn staged_step(published_at: Option<&String>, delay: u64, now: DateTime<Utc>) -> Rollover {
+    match published_at.and_then(|value| DateTime::parse_from_rfc3339(value).ok()) {
+        None => Rollover::Bundle,
+        Some(published_at) if published_at + Duration::seconds(delay as i64) <= now => {
+            Rollover::Issuer
+        }
+        Some(_) => Rollover::Unchanged,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn staged_root_waits_for_propagation() {
+        let now = Utc::now();
+        let published_at = (now - Duration::seconds(60)).to_rfc3339();
+        assert_eq!(
+            staged_step(Some(&published_at), 3600, now),
+            Rollover::Unchanged
+        );
+        assert_eq!(staged_step(Some(&published_at), 60, now), Rollover::Issuer);
+        assert_eq!(staged_step(Some(&published_at), 0, now), Rollover::Issuer);
+    }
+
+    #[test]
+    fn unpublished_root_is_published_again() {
+        let now = Utc::now();
+        assert_eq!(staged_step(None, 3600, now), Rollover::Bundle);
+        assert_eq!(
+            staged_step(Some(&"yesterday".to_string()), 3600, now),
+            Rollover::Bundle
+        );
+    }
+}
@@ -0,0 +1,321 @@
+use crate::utils;
+use anyhow::Result;
+use base64::{encode_config, URL_SAFE_NO_PAD};
+use chrono::{prelude::*, Duration};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    nid::Nid,
+    pkey::{Id, PKey},
+    sha::sha256,
+};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// Name of JWKS field in public secret
+pub const JWKS_FIELD: &str = "jwks.json";
+
+/// Build JSON Web Key (RFC 7517) from PEM encoded public key of service `name`
+///
+/// `kid` is `<name>.<thumbprint>` with RFC 7638 thumbprint, so it's stable for the same key
+pub fn jwk(public_key: &[u8], name: &str) -> Result<Value> {
+    let pkey = PKey::public_key_from_pem(public_key)?;
+
+    let (mut key, alg) = match pkey.id() {
+        Id::RSA => {
+            let rsa = pkey.rsa()?;
+            let key = json!({
+                "kty": "RSA",
+                "n": encode_config(&rsa.n().to_vec(), URL_SAFE_NO_PAD),
+                "e": encode_config(&rsa.e().to_vec(), URL_SAFE_NO_PAD),
+            });
+            (key, "RS256")
+        }
+        Id::EC => {
+            let ec = pkey.ec_key()?;
+            let (crv, alg) = match ec.group().curve_name() {
+                Some(Nid::X9_62_PRIME256V1) => ("P-256", "ES256"),
+                Some(Nid::SECP384R1) => ("P-384", "ES384"),
+                Some(Nid::SECP521R1) => ("P-521", "ES512"),
+                curve => return Err(anyhow::format_err!("Unsupported curve {:?}", curve)),
+            };
+            let mut x = BigNum::new()?;
+            let mut y = BigNum::new()?;
+            let mut ctx = BigNumContext::new()?;
+            ec.public_key()
+                .affine_coordinates_gfp(ec.group(), &mut x, &mut y, &mut ctx)?;
+            let size = ec.group().degree().div_ceil(8) as i32;
+            let key = json!({
+                "kty": "EC",
+                "crv": crv,
+                "x": encode_config(&x.to_vec_padded(size)?, URL_SAFE_NO_PAD),
+                "y": encode_config(&y.to_vec_padded(size)?, URL_SAFE_NO_PAD),
+            });
+            (key, alg)
+        }
+        Id::ED25519 => {
+            let key = json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "x": encode_config(&pkey.raw_public_key()?, URL_SAFE_NO_PAD),
+            });
+            (key, "EdDSA")
+        }
+        id => return Err(anyhow::format_err!("Unsupported key type {:?}", id)),
+    };
+
+    // Thumbprint is calculated from required members only, serialized in lexicographic order
+    let thumbprint = encode_config(&sha256(key.to_string().as_bytes()), URL_SAFE_NO_PAD);
+    let kid = format!("{}.{}", name, thumbprint);
+    let fields = key.as_object_mut().unwrap();
+    fields.insert("kid".into(), kid.into());
+    fields.insert("alg".into(), alg.into());
+    fields.insert("use".into(), "sig".into());
+    Ok(key)
+}
+
+/// Build private JSON Web Key from PEM encoded private key of service `name`
+pub fn private_jwk(private_key: &[u8], name: &str) -> Result<Value> {
+    let pkey = PKey::private_key_from_pem(private_key)?;
+    let mut key = jwk(&pkey.public_key_to_pem()?, name)?;
+    let fields = key.as_object_mut().unwrap();
+
+    let encode = |value: &[u8]| Value::from(encode_config(value, URL_SAFE_NO_PAD));
+    match pkey.id() {
+        Id::RSA => {
+            let rsa = pkey.rsa()?;
+            fields.insert("d".into(), encode(&rsa.d().to_vec()));
+            let params = [
+                ("p", rsa.p()),
+                ("q", rsa.q()),
+                ("dp", rsa.dmp1()),
+                ("dq", rsa.dmq1()),
+                ("qi", rsa.iqmp()),
+            ];
+            for (name, value) in params.iter() {
+                if let Some(value) = value {
+                    fields.insert((*name).into(), encode(&value.to_vec()));
+                }
+            }
+        }
+        Id::EC => {
+            let ec = pkey.ec_key()?;
+            let size = ec.group().degree().div_ceil(8) as i32;
+            fields.insert("d".into(), encode(&ec.private_key().to_vec_padded(size)?));
+        }
+        _ => {
+            fields.insert("d".into(), encode(&pkey.raw_private_key()?));
+        }
+    }
+    Ok(key)
+}
+
+/// Build JSON Web Key Set from fields of public secret in `namespace`
+///
+/// Every `.pem` field of a service is treated as a public key, broken keys are skipped
+pub fn jwks(namespace: &str, fields: &BTreeMap<String, Vec<u8>>) -> Value {
+    let keys: Vec<Value> = fields
+        .iter()
+        .filter(|(name, _)| name.ends_with(".pem"))
+        .filter_map(|(name, value)| {
+            let (namespace, service_name) = utils::parse_entry_name(namespace, name)?;
+            Some((
+                name,
+                value,
+                utils::qualified_name(Some(namespace), service_name),
+            ))
+        })
+        .filter_map(|(name, value, qualified)| match jwk(value, &qualified) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                warn!("Cannot convert <{}> to JWK: {}", name, e);
+                None
+            }
+        })
+        .collect();
+    json!({ "keys": keys })
+}
+
+/// Merge several key sets into one without duplicated keys
+pub fn merge(sets: Vec<Value>) -> Value {
+    let mut keys: BTreeMap<String, Value> = BTreeMap::new();
+    for set in sets {
+        if let Some(set_keys) = set.get("keys").and_then(Value::as_array) {
+            for key in set_keys {
+                if let Some(kid) = key.get("kid").and_then(Value::as_str) {
+                    keys.entry(kid.to_string()).or_insert_with(|| key.clone());
+                }
+            }
+        }
+    }
+    json!({ "keys": keys.into_values().collect::<Vec<_>>() })
+}
+
+/// Key sets of public secrets by namespace, served merged without listing secrets
+#[derive(Default)]
+pub struct Cache {
+    sets: BTreeMap<String, Value>,
+    /// Time of last load of all public secrets, `None` before the first one
+    loaded_at: Option<DateTime<Utc>>,
+}
+
+impl Cache {
+    /// Merged document, `None` if it's older than `max_age` and must be loaded again
+    pub fn document(&self, now: DateTime<Utc>, max_age: Duration) -> Option<Value> {
+        self.loaded_at
+            .filter(|loaded_at| now - *loaded_at < max_age)
+            .map(|_| merge(self.sets.values().cloned().collect()))
+    }
+
+    /// Replace key sets with ones of all public secrets
+    pub fn load(&mut self, sets: BTreeMap<String, Value>, now: DateTime<Utc>) {
+        self.sets = sets;
+        self.loaded_at = Some(now);
+    }
+
+    /// Replace key set of public secret in `namespace`, `None` if the secret is removed
+    pub fn refresh(&mut self, namespace: &str, set: Option<Value>) {
+        match set {
+            Some(set) => self.sets.insert(namespace.to_string(), set),
+            None => self.sets.remove(namespace),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::rsa::Rsa;
+
+    fn public_key() -> Vec<u8> {
+        let pkey = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        pkey.public_key_to_pem().unwrap()
+    }
+
+    fn kids(set: &Value) -> Vec<String> {
+        set["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|key| key["kid"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn kid_is_qualified_by_namespace() {
+        let public_key = public_key();
+        let shop = jwk(&public_key, "shop.api").unwrap();
+        let blog = jwk(&public_key, "blog.api").unwrap();
+
+        let shop_kid = shop["kid"].as_str().unwrap();
+        assert!(shop_kid.starts_with("shop.api."));
+        assert_eq!(
+            shop_kid.trim_start_matches("shop.api."),
+            blog["kid"]
+                .as_str()
+                .unwrap()
+                .trim_start_matches("blog.api.")
+        );
+    }
+
+    #[test]
+    fn key_set_names_keys_after_entries() {
+        let current = public_key();
+        let previous = public_key();
+        let mut fields = BTreeMap::new();
+        fields.insert(utils::public_key_name("api"), current);
+        fields.insert(
+            utils::published_name(Some("shop"), "blog", &utils::previous_key_name("api")),
+            previous,
+        );
+        fields.insert("ca.crt".to_string(), b"bundle".to_vec());
+        fields.insert("my_key.pem".to_string(), public_key());
+
+        let kids = kids(&jwks("blog", &fields));
+        assert_eq!(kids.len(), 2);
+        assert!(kids[0].starts_with("blog.api."));
+        assert!(kids[1].starts_with("shop.api."));
+    }
+
+    #[test]
+    fn merged_sets_have_no_duplicates() {
+        let mut fields = BTreeMap::new();
+        fields.insert(utils::public_key_name("api"), public_key());
+        let set = jwks("shop", &fields);
+
+        let merged = merge(vec![set.clone(), set]);
+        assert_eq!(kids(&merged).len(), 1);
+    }
+
+    #[test]
+    fn cache_is_refreshed_without_reload() {
+        let now = Utc::now();
+        let max_age = Duration::seconds(60);
+        let mut cache = Cache::default();
+        assert!(cache.document(now, max_age).is_none());
+
+        let mut fields = BTreeMap::new();
+        fields.insert(utils::public_key_name("api"), public_key());
+        let mut sets = BTreeMap::new();
+        sets.insert("shop".to_string(), jwks("shop", &fields));
+        cache.load(sets, now);
+        assert_eq!(kids(&cache.document(now, max_age).unwrap()).len(), 1);
+
+        let mut fields = BTreeMap::new();
+        fields.insert(utils::public_key_name("api"), public_key());
+        cache.refresh("blog", Some(jwks("blog", &fields)));
+        assert_eq!(kids(&cache.document(now, max_age).unwrap()).len(), 2);
+
+        cache.refresh("blog", None);
+        assert_eq!(kids(&cache.document(now, max_age).unwrap()).len(), 1);
+        assert!(cache.document(now + max_age, max_age).is_none());
+    }
+
+    #[test]
+    fn thumbprint_follows_rfc_7638() {
+        // Example key and thumbprint from RFC 7638, section 3.1
+        let n = "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_\
+                 BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_\
+                 FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4v\
+                 MQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw";
+        let n = BigNum::from_slice(&base64::decode_config(n, URL_SAFE_NO_PAD).unwrap()).unwrap();
+        let e = BigNum::from_u32(65537).unwrap();
+        let rsa = Rsa::from_public_components(n, e).unwrap();
+        let public_key = PKey::from_rsa(rsa).unwrap().public_key_to_pem().unwrap();
+
+        let key = jwk(&public_key, "default.api").unwrap();
+        assert_eq!(
+            key["kid"],
+            "default.api.NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+        assert_eq!(key["e"], "AQAB");
+    }
+
+    #[test]
+    fn private_jwk_extends_public_one() {
+        let pkey = PKey::generate_ed25519().unwrap();
+        let private =
+            private_jwk(&pkey.private_key_to_pem_pkcs8().unwrap(), "default.api").unwrap();
+        let mut public = jwk(&pkey.public_key_to_pem().unwrap(), "default.api").unwrap();
+
+        let d = base64::decode_config(private["d"].as_str().unwrap(), URL_SAFE_NO_PAD).unwrap();
+        assert_eq!(d, pkey.raw_private_key().unwrap());
+        public["d"] = private["d"].clone();
+        assert_eq!(private, public);
+    }
+
+    #[test]
+    fn ec_coordinates_are_padded_to_curve_size() {
+        let group = openssl::ec::EcGroup::from_curve_name(Nid::SECP521R1).unwrap();
+        let ec = openssl::ec::EcKey::generate(&group).unwrap();
+        let pkey = PKey::from_ec_key(ec).unwrap();
+
+        let key = private_jwk(&pkey.private_key_to_pem_pkcs8().unwrap(), "default.api").unwrap();
+        assert_eq!(key["crv"], "P-521");
+        assert_eq!(key["alg"], "ES512");
+        for field in &["x", "y", "d"] {
+            let value =
+                base64::decode_config(key[*field].as_str().unwrap(), URL_SAFE_NO_PAD).unwrap();
+            assert_eq!(value.len(), 66);
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use crate::{rsa_generator::KeyType, utils};
+use anyhow::Result;
+use chrono::prelude::*;
+use kube::{
+    api::{v1Secret, Api, Object, OwnerReference, PatchParams},
+    client::APIClient,
+};
+use serde_json::json;
+
+/// API group of KeyPair custom resource
+pub const GROUP: &str = "rsa.customer.keys";
+/// API version of KeyPair custom resource
+pub const VERSION: &str = "v1alpha1";
+/// Plural name of KeyPair custom resource
+pub const PLURAL: &str = "keypairs";
+
+pub type KeyPair = Object<KeyPairSpec, KeyPairStatus>;
+
+/// Desired key pair parameters, all fields fall back to operator settings
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyPairSpec {
+    /// Key algorithm
+    pub algorithm: Option<KeyType>,
+    /// RSA key size in bits
+    pub size: Option<u32>,
+    /// Name of private key secret, `<name>-rsa-token` by default
+    pub secret_name: Option<String>,
+    /// Namespaces for public key distribution
+    #[serde(default)]
+    pub public_namespaces: Vec<String>,
+    /// Scheduled rotation policy
+    pub rotation: Option<RotationPolicy>,
+}
+
+/// Rotation policy of a key pair, durations are in seconds
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RotationPolicy {
+    pub max_age: i64,
+    pub overlap: i64,
+}
+
+/// Observed key pair state
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyPairStatus {
+    /// SHA-256 fingerprint of current public key
+    pub fingerprint: Option<String>,
+    /// Time of current key generation
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub status: String,
+    pub reason: String,
+    pub message: String,
+    pub last_transition_time: String,
+}
+
+/// KeyPair api for all namespaces
+pub fn api(client: APIClient) -> Api<KeyPair> {
+    Api::customResource(client, PLURAL)
+        .group(GROUP)
+        .version(VERSION)
+}
+
+/// Name of private key secret for key pair
+pub fn secret_name(key_pair: &KeyPair) -> String {
+    key_pair
+        .spec
+        .secret_name
+        .clone()
+        .unwrap_or_else(|| utils::secret_name(key_pair.metadata.name.clone()))
+}
+
+/// Check that custom secret name of key pair is valid and not one of `reserved` operator secrets
+pub fn check_secret_name(key_pair: &KeyPair, reserved: &[String]) -> Result<()> {
+    let name = match &key_pair.spec.secret_name {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+    if !utils::is_valid_name(name) {
+        return Err(anyhow::format_err!(
+            "Invalid secret name '{}' of KeyPair {}",
+            name,
+            key_pair.metadata.name
+        ));
+    }
+    if reserved.contains(name) {
+        return Err(anyhow::format_err!(
+            "Secret {} is reserved by operator, KeyPair {} can't use it",
+            name,
+            key_pair.metadata.name
+        ));
+    }
+    Ok(())
+}
+
+/// Reason why existing `secret` can't hold keys of key pair, `None` if it's free to use
+///
+/// Secret must be created by operator, labelled with `managed` label, and owned by nothing but key pair
+pub fn secret_conflict(key_pair: &KeyPair, secret: &v1Secret, managed: &str) -> Option<String> {
+    if secret.metadata.labels.get(managed).map(String::as_str) != Some("true") {
+        return Some(format!(
+            "Secret {} is not managed by operator, KeyPair {} can't use it",
+            secret.metadata.name, key_pair.metadata.name
+        ));
+    }
+    secret
+        .metadata
+        .ownerReferences
+        .iter()
+        .find(|owner| Some(&owner.uid) != key_pair.metadata.uid.as_ref())
+        .map(|owner| {
+            format!(
+                "Secret {} belongs to {} {}, KeyPair {} can't use it",
+                secret.metadata.name, owner.kind, owner.name, key_pair.metadata.name
+            )
+        })
+}
+
+/// Reference to key pair for secrets owned by it
+pub fn owner_reference(key_pair: &KeyPair) -> Option<OwnerReference> {
+    Some(OwnerReference {
+        controller: false,
+        blockOwnerDeletion: false,
+        name: key_pair.metadata.name.clone(),
+        apiVersion: format!("{}/{}", GROUP, VERSION),
+        kind: "KeyPair".into(),
+        uid: key_pair.metadata.uid.clone()?,
+    })
+}
+
+/// Build new status keeping transition time when readiness is unchanged
+pub fn status(
+    key_pair: &KeyPair,
+    fingerprint: Option<String>,
+    created_at: Option<DateTime<Utc>>,
+    error: Option<String>,
+) -> KeyPairStatus {
+    let (status, reason, message) = match error {
+        None => (
+            "True",
+            "KeysReady",
+            "Keys are generated and published".into(),
+        ),
+        Some(e) => ("False", "ReconcileFailed", e),
+    };
+
+    let last_transition_time = key_pair
+        .status
+        .as_ref()
+        .and_then(|s| s.conditions.iter().find(|c| c.type_ == "Ready"))
+        .filter(|c| c.status == status)
+        .map(|c| c.last_transition_time.clone())
+        .unwrap_or_else(|| Utc::now().to_rfc3339());
+
+    KeyPairStatus {
+        fingerprint,
+        created_at: created_at.map(|t| t.to_rfc3339()),
+        conditions: vec![Condition {
+            type_: "Ready".into(),
+            status: status.into(),
+            reason: reason.into(),
+            message,
+            last_transition_time,
+        }],
+    }
+}
+
+/// Write key pair status if it was changed
+pub async fn update_status(
+    client: APIClient,
+    key_pair: &KeyPair,
+    status: KeyPairStatus,
+) -> Result<()> {
+    if key_pair.status.as_ref() == Some(&status) {
+        return Ok(());
+    }
+
+    let patch = json!({ "status": status });
+    api(client)
+        .within(
+            &key_pair
+                .metadata
+                .namespace
+                .clone()
+                .unwrap_or_else(|| "default".into()),
+        )
+        .patch_status(
+            &key_pair.metadata.name,
+            &PatchParams::default(),
+            serde_json::to_vec(&patch)?,
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANAGED: &str = "rsa.customer.keys/managed";
+
+    fn key_pair(secret_name: Option<&str>) -> KeyPair {
+        serde_json::from_value(json!({
+            "metadata": { "name": "api", "namespace": "shop", "uid": "api-uid" },
+            "spec": { "secretName": secret_name },
+        }))
+        .unwrap()
+    }
+
+    fn secret(managed: bool, owners: Vec<(&str, &str)>) -> v1Secret {
+        let owners: Vec<_> = owners
+            .into_iter()
+            .map(|(kind, uid)| {
+                json!({
+                    "apiVersion": "v1",
+                    "kind": kind,
+                    "name": "api",
+                    "uid": uid,
+                    "controller": false,
+                    "blockOwnerDeletion": false,
+                })
+            })
+            .collect();
+        let labels = if managed {
+            json!({ MANAGED: "true" })
+        } else {
+            json!({})
+        };
+        serde_json::from_value(json!({
+            "metadata": { "name": "api-rsa-token", "labels": labels, "ownerReferences": owners },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn secret_name_is_checked() {
+        let reserved = vec!["public-rsa-tokens".to_string(), "ca".to_string()];
+        assert!(check_secret_name(&key_pair(None), &reserved).is_ok());
+        assert!(check_secret_name(&key_pair(Some("api-keys")), &reserved).is_ok());
+        assert!(check_secret_name(&key_pair(Some("public-rsa-tokens")), &reserved).is_err());
+        assert!(check_secret_name(&key_pair(Some("ca")), &reserved).is_err());
+        assert!(check_secret_name(&key_pair(Some("Api_keys")), &reserved).is_err());
+    }
+
+    #[test]
+    fn only_own_managed_secret_is_used() {
+        let key_pair = key_pair(None);
+        assert!(secret_conflict(&key_pair, &secret(true, vec![]), MANAGED).is_none());
+        assert!(secret_conflict(
+            &key_pair,
+            &secret(true, vec![("KeyPair", "api-uid")]),
+            MANAGED
+        )
+        .is_none());
+        assert!(secret_conflict(&key_pair, &secret(false, vec![]), MANAGED).is_some());
+
+        let conflict = secret_conflict(
+            &key_pair,
+            &secret(
+                true,
+                vec![("KeyPair", "api-uid"), ("Deployment", "deploy-uid")],
+            ),
+            MANAGED,
+        )
+        .unwrap();
+        assert!(conflict.contains("Deployment api"));
+    }
+
+    #[test]
+    fn transition_time_is_kept_while_readiness_is_unchanged() {
+        let mut key_pair = key_pair(None);
+        let ready = status(&key_pair, Some("fp".into()), None, None);
+        assert_eq!(ready.conditions[0].status, "True");
+        assert_eq!(ready.conditions[0].reason, "KeysReady");
+
+        let mut observed = ready.clone();
+        observed.conditions[0].last_transition_time = "2020-01-01T00:00:00+00:00".into();
+        key_pair.status = Some(observed);
+
+        let unchanged = status(&key_pair, Some("fp2".into()), None, None);
+        assert_eq!(
+            unchanged.conditions[0].last_transition_time,
+            "2020-01-01T00:00:00+00:00"
+        );
+        assert_eq!(unchanged.fingerprint, Some("fp2".into()));
+
+        let failed = status(&key_pair, None, None, Some("broken".into()));
+        assert_eq!(failed.conditions[0].status, "False");
+        assert_eq!(failed.conditions[0].message, "broken");
+        assert_ne!(
+            failed.conditions[0].last_transition_time,
+            "2020-01-01T00:00:00+00:00"
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{settings::LeaderElection, utils};
+use anyhow::Result;
+use chrono::{prelude::*, Duration};
+use k8s_openapi::api::coordination::v1::LeaseSpec;
+use kube::{
+    api::{Api, Object, PostParams, Void},
+    client::APIClient,
+};
+use serde_json::{json, Value};
+
+pub type Lease = Object<LeaseSpec, Void>;
+
+/// Lease based leader elector
+#[derive(Clone)]
+pub struct Elector {
+    api: Api<Lease>,
+    config: LeaderElection,
+    /// Name of this operator instance
+    identity: String,
+}
+
+impl Elector {
+    pub fn new(client: APIClient, config: LeaderElection, identity: String) -> Self {
+        Elector {
+            api: Api::customResource(client, "leases")
+                .group("coordination.k8s.io")
+                .version("v1")
+                .within(&config.namespace),
+            config,
+            identity,
+        }
+    }
+
+    /// How often lease is renewed
+    pub fn renew_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs((self.config.lease_duration / 3).max(1) as u64)
+    }
+
+    /// Acquire or renew lease, returns `true` when this instance is leader
+    ///
+    /// Lease held by another instance is taken over only after it expired
+    pub async fn try_acquire(&self) -> Result<bool> {
+        let lease = match self.api.get(&self.config.lease_name).await {
+            Ok(lease) => lease,
+            Err(e) if utils::is_not_found(&e) => return self.create().await,
+            Err(e) => return Err(e.into()),
+        };
+
+        let spec = lease.spec.clone();
+        let holder = spec.holder_identity.clone().unwrap_or_default();
+        let now = Utc::now();
+
+        if holder != self.identity {
+            let duration = spec
+                .lease_duration_seconds
+                .map(i64::from)
+                .unwrap_or(self.config.lease_duration);
+            let expired = match spec.renew_time {
+                Some(renew_time) => renew_time.0 + Duration::seconds(duration) < now,
+                None => true,
+            };
+            if !holder.is_empty() && !expired {
+                return Ok(false);
+            }
+            info!(
+                "Take over lease {} from <{}>",
+                self.config.lease_name, holder
+            );
+        }
+
+        let (acquire_time, transitions) = if holder == self.identity {
+            (
+                spec.acquire_time.map(|t| t.0).unwrap_or(now),
+                spec.lease_transitions.unwrap_or(0),
+            )
+        } else {
+            (now, spec.lease_transitions.unwrap_or(0) + 1)
+        };
+
+        let data = json!({
+            "apiVersion": "coordination.k8s.io/v1",
+            "kind": "Lease",
+            "metadata": {
+                "name": self.config.lease_name,
+                // Replace fails with conflict if somebody else updated lease meanwhile
+                "resourceVersion": lease.metadata.resourceVersion,
+            },
+            "spec": self.spec(acquire_time, transitions),
+        });
+        let res = self
+            .api
+            .replace(
+                &self.config.lease_name,
+                &PostParams::default(),
+                serde_json::to_vec(&data)?,
+            )
+            .await;
+        conflict_as_lost(res)
+    }
+
+    async fn create(&self) -> Result<bool> {
+        info!("Create lease {}", self.config.lease_name);
+        let data = json!({
+            "apiVersion": "coordination.k8s.io/v1",
+            "kind": "Lease",
+            "metadata": {
+                "name": self.config.lease_name,
+            },
+            "spec": self.spec(Utc::now(), 0),
+        });
+        let res = self
+            .api
+            .create(&PostParams::default(), serde_json::to_vec(&data)?)
+            .await;
+        conflict_as_lost(res)
+    }
+
+    fn spec(&self, acquire_time: DateTime<Utc>, transitions: i32) -> Value {
+        json!({
+            "holderIdentity": self.identity,
+            "leaseDurationSeconds": self.config.lease_duration,
+            "acquireTime": acquire_time.to_rfc3339_opts(SecondsFormat::Micros, true),
+            "renewTime": Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            "leaseTransitions": transitions,
+        })
+    }
+}
+
+/// Another instance won the race for the lease
+fn conflict_as_lost(res: kube::Result<Lease>) -> Result<bool> {
+    match res {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
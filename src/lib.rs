@@ -10,7 +10,13 @@ extern crate prometheus;
 
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
 
+pub mod ca;
+pub mod jwks;
+pub mod keypair;
+pub mod leader;
 pub mod mounter;
+pub mod openssh;
+pub mod queue;
 pub mod rsa_generator;
 pub mod secret;
 pub mod settings;
@@ -18,6 +24,8 @@ pub mod settings;
 pub mod state;
 pub mod store;
 pub mod utils;
+pub mod webhook;
+pub mod workload;
 
 pub use settings::Settings;
 pub use state::Controller;
@@ -4,13 +4,13 @@ use log::{debug, error, info, trace, warn};
 use prometheus::{Encoder, TextEncoder};
 use std::env;
 
-use crate::settings::Settings;
-use actix_web::{get, App, HttpServer, Responder};
+use actix_web::{get, post, App, HttpServer, Responder};
 use actix_web::{
     middleware,
     web::{self, Data},
     HttpRequest, HttpResponse,
 };
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
 
 #[get("/metrics")]
 async fn metrics(c: Data<Controller>, _req: HttpRequest) -> impl Responder {
@@ -21,6 +21,22 @@ async fn metrics(c: Data<Controller>, _req: HttpRequest) -> impl Responder {
     HttpResponse::Ok().body(buffer)
 }
 
+#[get("/.well-known/jwks.json")]
+async fn jwks(c: Data<Controller>, _req: HttpRequest) -> impl Responder {
+    match c.jwks().await {
+        Ok(document) => HttpResponse::Ok().json(document),
+        Err(e) => {
+            error!("Cannot build JWKS: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[post("/mutate")]
+async fn mutate(c: Data<Controller>, review: web::Json<serde_json::Value>) -> impl Responder {
+    HttpResponse::Ok().json(webhook::review(&c, review.into_inner()).await)
+}
+
 #[get("/health")]
 async fn health(_: HttpRequest) -> impl Responder {
     HttpResponse::Ok().json("healthy")
@@ -52,10 +68,34 @@ async fn main() -> std::io::Result<()> {
             .await
             .expect("Failed to load kube config")
     };
-    let c = state::init(cfg, settings)
+    let c = state::init(cfg, settings.clone())
         .await
         .expect("Failed to initialize controller");
 
+    if let Some(webhook) = settings.webhook {
+        let mut tls =
+            SslAcceptor::mozilla_intermediate(SslMethod::tls()).expect("Failed to initialize TLS");
+        tls.set_private_key_file(&webhook.key, SslFiletype::PEM)
+            .expect("Failed to load webhook TLS key");
+        tls.set_certificate_chain_file(&webhook.cert)
+            .expect("Failed to load webhook TLS certificate");
+
+        let c = c.clone();
+        let address = format!("0.0.0.0:{}", webhook.port);
+        info!("Start admission webhook on {}", address);
+        HttpServer::new(move || {
+            App::new()
+                .data(c.clone())
+                .wrap(middleware::Logger::default())
+                .service(mutate)
+                .service(health)
+        })
+        .bind_openssl(&address, tls)
+        .unwrap_or_else(|_| panic!("Can not bind to {}", address))
+        .shutdown_timeout(0)
+        .run();
+    }
+
     HttpServer::new(move || {
         App::new()
             .data(c.clone())
@@ -63,6 +103,7 @@ async fn main() -> std::io::Result<()> {
             .service(index)
             .service(health)
             .service(metrics)
+            .service(jwks)
     })
     .bind("0.0.0.0:8080")
     .expect("Can not bind to 0.0.0.0:8080")
@@ -1,9 +1,12 @@
-use crate::{settings::Settings, state::Deployment, utils};
+use crate::{
+    settings::Settings,
+    utils,
+    workload::{Kind, Workload},
+};
 use anyhow::Result;
-use json_patch::merge;
-use k8s_openapi::{api::core::v1::PodSpec, serde_json};
+use k8s_openapi::serde_json;
 use kube::{
-    api::{Api, PatchParams, PatchStrategy},
+    api::{PatchParams, PatchStrategy},
     client::APIClient,
 };
 use serde_json::{json, value::Value};
@@ -11,51 +14,142 @@ use serde_json::{json, value::Value};
 #[derive(Clone)]
 pub struct Mounter {
     client: APIClient,
-    deployment: Deployment,
+    kind: Kind,
+    workload: Workload,
     settings: Settings,
+    /// Name of private key secret
+    secret_name: String,
+    /// Private key secrets mounted before, e.g. of KeyPair which is not referenced anymore
+    released: Vec<String>,
 }
 
-/// Mounter creates volumes in deployment
+/// Mounter creates volumes in workload pod template
 /// and restart pods after patching
 impl Mounter {
     pub async fn new(
         client: APIClient,
-        deployment: Deployment,
+        kind: Kind,
+        workload: Workload,
         settings: Settings,
     ) -> Result<Self> {
         Ok(Self {
+            secret_name: utils::secret_name(workload.metadata.name.clone()),
+            released: vec![],
             client,
-            deployment,
+            kind,
+            workload,
             settings,
         })
     }
 
+    /// Mount private key secret with custom name
+    pub fn with_secret_name(mut self, secret_name: String) -> Self {
+        self.secret_name = secret_name;
+        self
+    }
+
+    /// Remove volumes of released secrets together with mounting the current one
+    pub fn with_released(mut self, released: Vec<String>) -> Self {
+        self.released = released;
+        self
+    }
+
     pub async fn mount(&self) -> Result<()> {
+        if !self.kind.is_template_mutable() {
+            warn!(
+                "Pod template of {} {:?} is immutable, mount volumes manually or enable webhook",
+                self.kind, self.workload.metadata.name
+            );
+            return Ok(());
+        }
+
+        let pod_spec_patch = match self.pod_spec_patch(self.kind.pod_spec(&self.workload)?)? {
+            Some(patch) => patch,
+            None => {
+                debug!(
+                    "Volumes are already mounted to {}: {:?}",
+                    self.kind, self.workload.metadata.name
+                );
+                return Ok(());
+            }
+        };
         info!(
-            "Mount volumes to deploy: {:?}",
-            self.deployment.metadata.name
+            "Mount volumes to {}: {:?}",
+            self.kind, self.workload.metadata.name
         );
-        let volumes_patch = self.make_patch().await?;
-        let container_volumes = self
-            .make_containers_patch(self.deployment.clone().spec.template.spec)
-            .await?;
+        let patch = self.kind.template_patch(pod_spec_patch);
+
+        self.apply(patch).await
+    }
 
-        let patch = json!({
-            "spec": {
-                "template": {
-                    "spec": {
-                        "containers": container_volumes,
-                        "volumes": volumes_patch.get("volumes"),
-                    },
-                }
+    /// Merge patch of pod spec with operator volumes and mounts
+    ///
+    /// Existing volumes and mounts are kept, operator ones are added by name
+    /// or updated in place. Returns `None` when pod spec is already up to date
+    pub fn pod_spec_patch(&self, pod_spec: &Value) -> Result<Option<Value>> {
+        let mounts = self.mounts()?;
+        for mount in mounts.iter().filter(|m| is_user_volume(pod_spec, &m.name)) {
+            warn!(
+                "Volume {} of {} {:?} has another source, keys are not mounted",
+                mount.name, self.kind, self.workload.metadata.name
+            );
+        }
+        for mount in mounts.iter() {
+            let unknown = mount.unknown_containers(pod_spec);
+            if !unknown.is_empty() {
+                warn!(
+                    "Containers {:?} selected for volume {} are not in {} {:?}",
+                    unknown, mount.name, self.kind, self.workload.metadata.name
+                );
             }
-        });
+        }
+        Ok(mount_patch(pod_spec, &mounts))
+    }
+
+    /// Check that operator volumes are declared and mounted to selected containers
+    pub fn is_mounted(&self) -> Result<bool> {
+        if !self.kind.is_template_mutable() {
+            return Ok(true);
+        }
+
+        let pod_spec = self.kind.pod_spec(&self.workload)?;
+        Ok(self.pod_spec_patch(pod_spec)?.is_none())
+    }
+
+    /// Remove operator volumes and mounts from workload
+    ///
+    /// Volumes with the same name which don't reference operator secrets are user ones and kept
+    pub async fn unmount(&self) -> Result<()> {
+        if !self.kind.is_template_mutable() {
+            return Ok(());
+        }
+
+        let pod_spec = self.kind.pod_spec(&self.workload)?;
+        let pod_spec_patch = match unmount_patch(pod_spec, &self.volume_names()) {
+            Some(patch) => patch,
+            None => {
+                debug!(
+                    "Volumes are not mounted to {}: {:?}",
+                    self.kind, self.workload.metadata.name
+                );
+                return Ok(());
+            }
+        };
+        info!(
+            "Unmount volumes from {}: {:?}",
+            self.kind, self.workload.metadata.name
+        );
+        let patch = self.kind.template_patch(pod_spec_patch);
 
+        self.apply(patch).await
+    }
+
+    async fn apply(&self, patch: Value) -> Result<()> {
         info!("Applyed patch: {}", patch);
 
-        let client = Api::v1Deployment(self.client.clone()).within(
+        let client = self.kind.api(self.client.clone()).within(
             &self
-                .deployment
+                .workload
                 .clone()
                 .metadata
                 .namespace
@@ -64,7 +158,7 @@ impl Mounter {
 
         client
             .patch(
-                &self.deployment.metadata.name,
+                &self.workload.metadata.name,
                 &PatchParams::default(),
                 serde_json::to_vec(&patch)?,
             )
@@ -72,57 +166,604 @@ impl Mounter {
         Ok(())
     }
 
-    async fn make_containers_patch(&self, pod_spec: Option<PodSpec>) -> Result<Value> {
-        let containers = pod_spec
-            .map(|s| s.containers)
-            .ok_or_else(|| {
-                format!(
-                    "Missing containers for deployment '{}'",
-                    self.deployment.metadata.name
-                )
+    /// Operator volumes with their mount paths, volume is named after its secret
+    fn mounts(&self) -> Result<Vec<Mount>> {
+        let mut mounts = vec![
+            self.make_mount(
+                "private",
+                &self.secret_name,
+                &self.settings.volumes.private.path,
+            )?,
+            self.make_mount(
+                "public",
+                &self.settings.secrets.public_name,
+                &self.settings.volumes.public.path,
+            )?,
+        ];
+        // Disabled mounts are removed
+        mounts.extend(
+            self.released
+                .iter()
+                .filter(|name| **name != self.secret_name)
+                .map(|name| Mount {
+                    name: name.clone(),
+                    path: String::new(),
+                    containers: None,
+                    items: None,
+                    enabled: false,
+                }),
+        );
+        Ok(mounts)
+    }
+
+    /// Mount of secret `name` with overrides from `<side>-*` workload annotations
+    fn make_mount(&self, side: &str, name: &str, default_path: &str) -> Result<Mount> {
+        let enabled = match self.annotation(&format!("{}-mount", side)) {
+            Some("true") | None => true,
+            Some("false") => false,
+            Some(value) => return Err(self.invalid(side, "mount", value)),
+        };
+
+        let path = match self.annotation(&format!("{}-path", side)) {
+            Some(path) if utils::is_valid_mount_path(path) => path,
+            Some(path) => return Err(self.invalid(side, "path", path)),
+            None => default_path,
+        };
+
+        let items = match self.annotation(&format!("{}-files", side)) {
+            Some(value) => Some(
+                split_list(value)
+                    .into_iter()
+                    .map(|item| {
+                        let (key, file) = match item.find('=') {
+                            Some(idx) => (&item[..idx], &item[idx + 1..]),
+                            None => (item.as_str(), item.as_str()),
+                        };
+                        if utils::is_valid_key(key) && utils::is_valid_file_path(file) {
+                            Ok((key.to_string(), file.to_string()))
+                        } else {
+                            Err(self.invalid(side, "files", value))
+                        }
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            None => None,
+        };
+
+        Ok(Mount {
+            name: name.into(),
+            path: path.into(),
+            containers: self
+                .annotation(&format!("{}-containers", side))
+                .map(split_list),
+            items,
+            enabled,
+        })
+    }
+
+    fn annotation(&self, name: &str) -> Option<&str> {
+        self.workload
+            .metadata
+            .annotations
+            .get(&self.settings.annotation_key(name))
+            .map(String::as_str)
+    }
+
+    fn invalid(&self, side: &str, name: &str, value: &str) -> anyhow::Error {
+        anyhow::format_err!(
+            "Invalid {}-{} annotation '{}' of {} '{}'",
+            side,
+            name,
+            value,
+            self.kind,
+            self.workload.metadata.name
+        )
+    }
+
+    /// Names of volumes managed by operator
+    fn volume_names(&self) -> Vec<String> {
+        let mut names = vec![
+            self.secret_name.clone(),
+            self.settings.secrets.public_name.clone(),
+        ];
+        names.extend(self.released.iter().cloned());
+        names
+    }
+}
+
+/// Operator volume mounted to containers
+struct Mount {
+    name: String,
+    path: String,
+    /// Selected container names, `None` selects all containers
+    containers: Option<Vec<String>>,
+    /// Secret keys with file names they are projected to, `None` projects all keys
+    items: Option<Vec<(String, String)>>,
+    enabled: bool,
+}
+
+impl Mount {
+    fn is_selected(&self, container: &str) -> bool {
+        match &self.containers {
+            Some(containers) => containers.iter().any(|name| name == container),
+            None => true,
+        }
+    }
+
+    /// Selected container names missing in pod spec, e.g. mistyped ones
+    fn unknown_containers<'a>(&'a self, pod_spec: &Value) -> Vec<&'a str> {
+        let containers = pod_spec["containers"].as_array();
+        self.containers
+            .iter()
+            .flatten()
+            .map(String::as_str)
+            .filter(|name| {
+                !containers
+                    .into_iter()
+                    .flatten()
+                    .any(|container| container["name"] == *name)
             })
-            .map_err(anyhow::Error::msg)?;
-        info!(
-            "Containers for deployment: {:?}  -- {:?}",
-            self.deployment.metadata.name, containers
-        );
-
-        let containers_with_volumes: Vec<Value> = containers
-            .into_iter()
-            .map(|c| {
-                let mut original = json!(c);
-                let patch = json!({
-                    "volumeMounts": [{
-                        "name": utils::secret_name(self.deployment.metadata.name.clone()),
-                        "mountPath": self.settings.volumes.private.path,
-                    },{
-                        "name": self.settings.secrets.public_name,
-                        "mountPath": self.settings.volumes.public.path,
-                    }],
-                });
-                merge(&mut original, &patch);
-                original
+            .collect()
+    }
+}
+
+/// Merge patch adding operator volumes and mounts to raw pod spec, `None` if it's up to date
+///
+/// Merge patch replaces lists, so they are sent in full with all fields of existing items
+fn mount_patch(pod_spec: &Value, operator_mounts: &[Mount]) -> Option<Value> {
+    let mut changed = false;
+
+    // User volume with operator volume name is left alone together with its mounts
+    let operator_mounts: Vec<&Mount> = operator_mounts
+        .iter()
+        .filter(|m| !is_user_volume(pod_spec, &m.name))
+        .collect();
+
+    let mut volumes = list(pod_spec, "volumes");
+    for mount in operator_mounts.iter() {
+        changed |= if mount.enabled {
+            merge_volume(&mut volumes, &mount.name, &mount.items)
+        } else {
+            remove_volume(&mut volumes, &mount.name)
+        };
+    }
+
+    let mut containers = list(pod_spec, "containers");
+    for container in containers.iter_mut() {
+        let container_name = container["name"].as_str().unwrap_or_default().to_string();
+        let mut mounts = list(container, "volumeMounts");
+        for mount in operator_mounts.iter() {
+            changed |= if mount.enabled && mount.is_selected(&container_name) {
+                merge_mount(&mut mounts, &mount.name, &mount.path)
+            } else {
+                remove_mount(&mut mounts, &mount.name)
+            };
+        }
+        container["volumeMounts"] = Value::Array(mounts);
+    }
+
+    if !changed {
+        return None;
+    }
+    Some(json!({
+        "containers": containers,
+        "volumes": volumes,
+    }))
+}
+
+/// Merge patch removing operator volumes `names` and their mounts, `None` if nothing is mounted
+fn unmount_patch(pod_spec: &Value, names: &[String]) -> Option<Value> {
+    let mut changed = false;
+
+    let mut volumes = list(pod_spec, "volumes");
+    let mut removed = vec![];
+    for name in names.iter() {
+        changed |= remove_volume(&mut volumes, name);
+        // User volume with the same name keeps its mounts
+        if !volumes.iter().any(|v| v["name"] == name.as_str()) {
+            removed.push(name);
+        }
+    }
+
+    let mut containers = list(pod_spec, "containers");
+    for container in containers.iter_mut() {
+        if let Some(mounts) = container["volumeMounts"].as_array_mut() {
+            for name in removed.iter() {
+                changed |= remove_mount(mounts, name);
+            }
+        }
+    }
+
+    if !changed {
+        return None;
+    }
+    Some(json!({
+        "containers": containers,
+        "volumes": volumes,
+    }))
+}
+
+/// Check that pod spec carries operator volumes: public secret or `<name>-rsa-token` volume named after its secret
+pub fn has_operator_volumes(pod_spec: &Value, public_name: &str) -> bool {
+    list(pod_spec, "volumes").iter().any(|volume| {
+        let name = volume["name"].as_str().unwrap_or_default();
+        volume["secret"]["secretName"] == name
+            && (name == public_name || name.ends_with("-rsa-token"))
+    })
+}
+
+/// List field of raw object, missing one is empty
+fn list(object: &Value, name: &str) -> Vec<Value> {
+    object[name].as_array().cloned().unwrap_or_default()
+}
+
+/// Check that pod spec has volume `name` which doesn't reference secret `name`
+fn is_user_volume(pod_spec: &Value, name: &str) -> bool {
+    pod_spec["volumes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(|v| v["name"] == name && v["secret"]["secretName"] != name)
+}
+
+/// Add secret volume named `name` or update items of existing one, returns `true` if changed
+///
+/// Other fields of existing secret volume, e.g. `defaultMode`, are kept.
+/// User volume with the same name but another source is never replaced
+fn merge_volume(
+    volumes: &mut Vec<Value>,
+    name: &str,
+    items: &Option<Vec<(String, String)>>,
+) -> bool {
+    let existing = volumes.iter_mut().find(|v| v["name"] == name);
+    let mut secret = match existing.as_ref() {
+        Some(existing) if existing["secret"]["secretName"] != name => return false,
+        Some(existing) if same_items(&existing["secret"]["items"], items) => return false,
+        Some(existing) => existing["secret"].clone(),
+        None => json!({}),
+    };
+
+    secret["secretName"] = name.into();
+    match items {
+        Some(items) => {
+            secret["items"] = items
+                .iter()
+                .map(|(key, path)| json!({ "key": key, "path": path }))
+                .collect();
+        }
+        None => {
+            if let Some(secret) = secret.as_object_mut() {
+                secret.remove("items");
+            }
+        }
+    }
+    let volume = json!({ "name": name, "secret": secret });
+    match existing {
+        Some(existing) => *existing = volume,
+        None => volumes.push(volume),
+    }
+    true
+}
+
+/// Compare projected keys ignoring file modes defaulted by API server
+fn same_items(existing: &Value, items: &Option<Vec<(String, String)>>) -> bool {
+    let existing = existing.as_array().map(|existing| {
+        existing
+            .iter()
+            .map(|item| {
+                (
+                    item["key"].as_str().unwrap_or_default().to_string(),
+                    item["path"].as_str().unwrap_or_default().to_string(),
+                )
             })
-            .collect();
+            .collect::<Vec<_>>()
+    });
+    existing.as_ref() == items.as_ref()
+}
 
-        Ok(containers_with_volumes.into())
+/// Remove operator volume of secret `name`, returns `true` if changed
+fn remove_volume(volumes: &mut Vec<Value>, name: &str) -> bool {
+    let len = volumes.len();
+    volumes.retain(|v| v["name"] != name || v["secret"]["secretName"] != name);
+    volumes.len() != len
+}
+
+/// Add mount of volume `name` or update its path, returns `true` if changed
+///
+/// Other fields of existing mount, e.g. `readOnly`, are kept
+fn merge_mount(mounts: &mut Vec<Value>, name: &str, path: &str) -> bool {
+    match mounts.iter_mut().find(|m| m["name"] == name) {
+        Some(existing) if existing["mountPath"] == path => return false,
+        Some(existing) => existing["mountPath"] = path.into(),
+        None => mounts.push(json!({ "name": name, "mountPath": path })),
     }
+    true
+}
+
+/// Comma separated annotation value
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
 
-    async fn make_patch(&self) -> Result<Value> {
-        let patch = json!({
-            "volumes": [{
-                    "name": utils::secret_name(self.deployment.metadata.name.clone()),
-                    "secret": {
-                        "secretName": utils::secret_name(self.deployment.metadata.name.clone()),
-                    },
+/// Remove mount of volume `name` from container not selected for it, returns `true` if changed
+fn remove_mount(mounts: &mut Vec<Value>, name: &str) -> bool {
+    let len = mounts.len();
+    mounts.retain(|m| m["name"] != name);
+    mounts.len() != len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mount(name: &str, path: &str) -> Mount {
+        Mount {
+            name: name.into(),
+            path: path.into(),
+            containers: None,
+            items: None,
+            enabled: true,
+        }
+    }
+
+    fn pod_spec() -> Value {
+        json!({
+            "containers": [{
+                "name": "app",
+                "image": "app:1",
+                // Not known to k8s-openapi v1_16
+                "startupProbe": { "exec": { "command": ["true"] } },
+                "volumeMounts": [{ "name": "cache", "mountPath": "/cache" }],
             }, {
-                "name": self.settings.secrets.public_name.clone(),
+                "name": "sidecar",
+                "image": "proxy:1",
+            }],
+            "volumes": [
+                { "name": "cache", "ephemeral": { "volumeClaimTemplate": {} } },
+            ],
+        })
+    }
+
+    fn apply(pod_spec: &Value, patch: &Value) -> Value {
+        let mut patched = pod_spec.clone();
+        json_patch::merge(&mut patched, patch);
+        patched
+    }
+
+    #[test]
+    fn mounting_is_idempotent_and_keeps_unknown_fields() {
+        let mounts = vec![
+            mount("api-rsa-token", "/etc/keys"),
+            mount("public-rsa-tokens", "/etc/public"),
+        ];
+        let patch = mount_patch(&pod_spec(), &mounts).unwrap();
+        let mounted = apply(&pod_spec(), &patch);
+
+        assert_eq!(mount_patch(&mounted, &mounts), None);
+        assert_eq!(
+            mounted["containers"][0]["startupProbe"],
+            pod_spec()["containers"][0]["startupProbe"]
+        );
+        assert_eq!(mounted["volumes"][0], pod_spec()["volumes"][0]);
+        assert_eq!(
+            mounted["containers"][0]["volumeMounts"],
+            json!([
+                { "name": "cache", "mountPath": "/cache" },
+                { "name": "api-rsa-token", "mountPath": "/etc/keys" },
+                { "name": "public-rsa-tokens", "mountPath": "/etc/public" },
+            ])
+        );
+        assert_eq!(
+            mounted["volumes"][1],
+            json!({ "name": "api-rsa-token", "secret": { "secretName": "api-rsa-token" } })
+        );
+    }
+
+    #[test]
+    fn released_secret_is_replaced_in_one_patch() {
+        let old = vec![mount("old-keys", "/etc/keys")];
+        let mounted = apply(&pod_spec(), &mount_patch(&pod_spec(), &old).unwrap());
+
+        let released = Mount {
+            enabled: false,
+            ..mount("old-keys", "")
+        };
+        let new = vec![mount("new-keys", "/etc/keys"), released];
+        let switched = apply(&mounted, &mount_patch(&mounted, &new).unwrap());
+
+        assert_eq!(
+            switched["containers"][0]["volumeMounts"],
+            json!([
+                { "name": "cache", "mountPath": "/cache" },
+                { "name": "new-keys", "mountPath": "/etc/keys" },
+            ])
+        );
+        assert_eq!(switched["volumes"].as_array().unwrap().len(), 2);
+        assert_eq!(switched["volumes"][1]["name"], "new-keys");
+        assert_eq!(mount_patch(&switched, &new), None);
+    }
+
+    #[test]
+    fn unmounting_is_idempotent_and_keeps_user_volumes() {
+        let names = vec!["api-rsa-token".to_string(), "public-rsa-tokens".to_string()];
+        let mut spec = pod_spec();
+        // User volume sharing the name of public secret volume isn't operator one
+        spec["volumes"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({ "name": "public-rsa-tokens", "emptyDir": {} }));
+        spec["containers"][0]["volumeMounts"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({ "name": "public-rsa-tokens", "mountPath": "/public" }));
+
+        let mounted = apply(
+            &spec,
+            &mount_patch(&spec, &[mount("api-rsa-token", "/etc/keys")]).unwrap(),
+        );
+        let unmounted = apply(&mounted, &unmount_patch(&mounted, &names).unwrap());
+
+        assert_eq!(unmount_patch(&unmounted, &names), None);
+        assert_eq!(unmounted["volumes"], spec["volumes"]);
+        assert_eq!(
+            unmounted["containers"][0]["volumeMounts"],
+            spec["containers"][0]["volumeMounts"]
+        );
+        assert_eq!(unmount_patch(&spec, &names), None);
+    }
+
+    #[test]
+    fn mounts_only_selected_containers() {
+        let mut private = mount("api-rsa-token", "/etc/keys");
+        private.containers = Some(vec!["sidecar".into()]);
+
+        let mounted = apply(&pod_spec(), &mount_patch(&pod_spec(), &[private]).unwrap());
+        assert_eq!(
+            mounted["containers"][0]["volumeMounts"],
+            json!([{ "name": "cache", "mountPath": "/cache" }])
+        );
+        assert_eq!(
+            mounted["containers"][1]["volumeMounts"],
+            json!([{ "name": "api-rsa-token", "mountPath": "/etc/keys" }])
+        );
+    }
+
+    #[test]
+    fn unknown_containers_are_reported() {
+        let mut private = mount("api-rsa-token", "/etc/keys");
+        assert!(private.unknown_containers(&pod_spec()).is_empty());
+
+        private.containers = Some(vec!["sidecar".into(), "sidecra".into()]);
+        assert_eq!(private.unknown_containers(&pod_spec()), vec!["sidecra"]);
+    }
+
+    #[test]
+    fn disabled_mount_is_removed() {
+        let mounts = vec![mount("api-rsa-token", "/etc/keys")];
+        let mounted = apply(&pod_spec(), &mount_patch(&pod_spec(), &mounts).unwrap());
+
+        let mut disabled = mount("api-rsa-token", "/etc/keys");
+        disabled.enabled = false;
+        let removed = apply(&mounted, &mount_patch(&mounted, &[disabled]).unwrap());
+        assert_eq!(removed["volumes"], pod_spec()["volumes"]);
+        assert_eq!(
+            removed["containers"][0]["volumeMounts"],
+            pod_spec()["containers"][0]["volumeMounts"]
+        );
+    }
+
+    #[test]
+    fn merge_volume_updates_items_and_keeps_secret_fields() {
+        let mut volumes = vec![json!({
+            "name": "api-rsa-token",
+            "secret": { "secretName": "api-rsa-token", "defaultMode": 256 },
+        })];
+        assert!(!merge_volume(&mut volumes, "api-rsa-token", &None));
+
+        let items = Some(vec![("private.pem".to_string(), "jwt.key".to_string())]);
+        assert!(merge_volume(&mut volumes, "api-rsa-token", &items));
+        assert_eq!(
+            volumes,
+            vec![json!({
+                "name": "api-rsa-token",
                 "secret": {
-                    "secretName": self.settings.secrets.public_name,
+                    "secretName": "api-rsa-token",
+                    "defaultMode": 256,
+                    "items": [{ "key": "private.pem", "path": "jwt.key" }],
                 },
-            }
-            ],
-        });
-        Ok(patch)
+            })]
+        );
+
+        // File mode defaulted by API server doesn't make items different
+        volumes[0]["secret"]["items"][0]["mode"] = 256.into();
+        assert!(!merge_volume(&mut volumes, "api-rsa-token", &items));
+
+        assert!(merge_volume(&mut volumes, "api-rsa-token", &None));
+        assert_eq!(volumes[0]["secret"].get("items"), None);
+    }
+
+    #[test]
+    fn merge_volume_keeps_user_volume_with_same_name() {
+        let user = vec![
+            json!({ "name": "api-rsa-token", "emptyDir": {} }),
+            json!({ "name": "public-rsa-tokens", "secret": { "secretName": "keys" } }),
+        ];
+        let mut volumes = user.clone();
+        assert!(!merge_volume(&mut volumes, "api-rsa-token", &None));
+        assert!(!merge_volume(&mut volumes, "public-rsa-tokens", &None));
+        assert_eq!(volumes, user);
+    }
+
+    #[test]
+    fn mount_patch_skips_user_volume_with_same_name() {
+        let mut spec = pod_spec();
+        spec["volumes"] = json!([{ "name": "api-rsa-token", "emptyDir": {} }]);
+        spec["containers"][0]["volumeMounts"] =
+            json!([{ "name": "api-rsa-token", "mountPath": "/cache" }]);
+        assert_eq!(
+            mount_patch(&spec, &[mount("api-rsa-token", "/etc/keys")]),
+            None
+        );
+    }
+
+    #[test]
+    fn remove_volume_keeps_user_volume_with_same_name() {
+        let mut volumes = vec![
+            json!({ "name": "keys", "emptyDir": {} }),
+            json!({ "name": "api-rsa-token", "secret": { "secretName": "api-rsa-token" } }),
+        ];
+        assert!(!remove_volume(&mut volumes, "keys"));
+        assert!(remove_volume(&mut volumes, "api-rsa-token"));
+        assert!(!remove_volume(&mut volumes, "api-rsa-token"));
+        assert_eq!(volumes, vec![json!({ "name": "keys", "emptyDir": {} })]);
+    }
+
+    #[test]
+    fn merge_mount_updates_path_and_keeps_fields() {
+        let mut mounts = vec![json!({ "name": "keys", "mountPath": "/keys", "readOnly": true })];
+        assert!(!merge_mount(&mut mounts, "keys", "/keys"));
+        assert!(merge_mount(&mut mounts, "keys", "/etc/keys"));
+        assert!(merge_mount(&mut mounts, "public", "/etc/public"));
+        assert_eq!(
+            mounts,
+            vec![
+                json!({ "name": "keys", "mountPath": "/etc/keys", "readOnly": true }),
+                json!({ "name": "public", "mountPath": "/etc/public" }),
+            ]
+        );
+
+        assert!(remove_mount(&mut mounts, "keys"));
+        assert!(!remove_mount(&mut mounts, "keys"));
+        assert_eq!(mounts.len(), 1);
+    }
+
+    #[test]
+    fn split_list_skips_blanks() {
+        assert_eq!(split_list(" app, ,sidecar,"), vec!["app", "sidecar"]);
+        assert!(split_list("").is_empty());
+    }
+
+    #[test]
+    fn operator_volumes_are_recognized() {
+        let user = json!({ "volumes": [
+            { "name": "config", "secret": { "secretName": "config" } },
+            { "name": "api-rsa-token", "secret": { "secretName": "other" } },
+        ]});
+        assert!(!has_operator_volumes(&user, "public-rsa-tokens"));
+        assert!(!has_operator_volumes(&json!({}), "public-rsa-tokens"));
+
+        let private = json!({ "volumes": [
+            { "name": "api-rsa-token", "secret": { "secretName": "api-rsa-token" } },
+        ]});
+        assert!(has_operator_volumes(&private, "public-rsa-tokens"));
+
+        let public = json!({ "volumes": [
+            { "name": "public-rsa-tokens", "secret": { "secretName": "public-rsa-tokens" } },
+        ]});
+        assert!(has_operator_volumes(&public, "public-rsa-tokens"));
     }
 }
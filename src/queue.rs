@@ -0,0 +1,236 @@
+use crate::settings::Retry;
+use futures::{lock::Mutex, Future};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+/// Work queue of failed items with per-key exponential backoff
+#[derive(Clone)]
+pub struct RetryQueue<T> {
+    config: Retry,
+    /// Items waiting for retry with their due time
+    pending: Arc<RwLock<BTreeMap<String, (T, Instant)>>>,
+    /// Failures count of every failing key
+    attempts: Arc<RwLock<BTreeMap<String, u32>>>,
+}
+
+impl<T: Clone> RetryQueue<T> {
+    pub fn new(config: Retry) -> Self {
+        RetryQueue {
+            config,
+            pending: Arc::new(RwLock::new(BTreeMap::new())),
+            attempts: Arc::new(RwLock::new(BTreeMap::new())),
+        }
+    }
+
+    /// Schedule retry of failed item, newer item replaces pending one with the same key
+    ///
+    /// Returns `false` when retries of the key are exhausted and it's dropped
+    pub fn fail(&self, key: &str, item: T) -> bool {
+        let attempts = {
+            let mut attempts = self.attempts.write().unwrap();
+            let count = attempts.entry(key.into()).or_insert(0);
+            *count += 1;
+            *count
+        };
+        if attempts > self.config.max_retries {
+            self.forget(key);
+            return false;
+        }
+
+        let due = Instant::now() + self.backoff(attempts);
+        self.pending
+            .write()
+            .unwrap()
+            .insert(key.into(), (item, due));
+        true
+    }
+
+    /// Drop key after successful processing
+    pub fn forget(&self, key: &str) {
+        self.pending.write().unwrap().remove(key);
+        self.attempts.write().unwrap().remove(key);
+    }
+
+    /// Take items which are due for retry
+    pub fn due(&self) -> Vec<(String, T)> {
+        let now = Instant::now();
+        let mut pending = self.pending.write().unwrap();
+        let keys: Vec<String> = pending
+            .iter()
+            .filter(|(_, (_, due))| *due <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.into_iter()
+            .filter_map(|key| pending.remove(&key).map(|(item, _)| (key, item)))
+            .collect()
+    }
+
+    /// Number of items waiting for retry
+    pub fn depth(&self) -> usize {
+        self.pending.read().unwrap().len()
+    }
+
+    /// Delay before `attempts`-th retry, doubled after every failure
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+        let delay = self.config.base_delay.saturating_mul(factor);
+        Duration::from_secs(delay.min(self.config.max_delay))
+    }
+}
+
+/// Per-key locks, work on the same key is never done concurrently
+#[derive(Clone, Default)]
+pub struct KeyLocks {
+    locks: Arc<RwLock<BTreeMap<String, Arc<Mutex<()>>>>>,
+}
+
+impl KeyLocks {
+    pub fn new() -> Self {
+        KeyLocks::default()
+    }
+
+    /// Run `work` once no other work holds `key`
+    pub async fn run<T>(&self, key: &str, work: impl Future<Output = T>) -> T {
+        let lock = self
+            .locks
+            .write()
+            .unwrap()
+            .entry(key.into())
+            .or_default()
+            .clone();
+        let result = {
+            let _guard = lock.lock().await;
+            work.await
+        };
+
+        // Lock nobody else holds or waits for is dropped, so keys don't pile up
+        let mut locks = self.locks.write().unwrap();
+        if Arc::strong_count(&lock) == 2 {
+            locks.remove(key);
+        }
+        result
+    }
+
+    /// Number of keys being worked on
+    pub fn len(&self) -> usize {
+        self.locks.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{channel::oneshot, executor::block_on, join};
+    use std::sync::Mutex as StdMutex;
+
+    fn queue(max_retries: u32, base_delay: u64, max_delay: u64) -> RetryQueue<u32> {
+        RetryQueue::new(Retry {
+            max_retries,
+            base_delay,
+            max_delay,
+        })
+    }
+
+    #[test]
+    fn backoff_is_doubled_and_capped() {
+        let queue = queue(10, 2, 20);
+        let delays: Vec<u64> = (1..=6).map(|n| queue.backoff(n).as_secs()).collect();
+        assert_eq!(delays, vec![2, 4, 8, 16, 20, 20]);
+        assert_eq!(queue.backoff(u32::MAX).as_secs(), 20);
+    }
+
+    #[test]
+    fn key_is_dropped_after_max_retries() {
+        let queue = queue(2, 60, 60);
+        assert!(queue.fail("default/api", 1));
+        assert!(queue.fail("default/api", 2));
+        assert!(!queue.fail("default/api", 3));
+        assert_eq!(queue.depth(), 0);
+
+        // Dropped key starts counting again
+        assert!(queue.fail("default/api", 4));
+        assert_eq!(queue.depth(), 1);
+    }
+
+    #[test]
+    fn forget_resets_attempts() {
+        let queue = queue(1, 60, 60);
+        assert!(queue.fail("default/api", 1));
+        queue.forget("default/api");
+        assert_eq!(queue.depth(), 0);
+        assert!(queue.fail("default/api", 2));
+    }
+
+    #[test]
+    fn only_due_items_are_taken_in_key_order() {
+        let waiting = queue(10, 60, 60);
+        assert!(waiting.fail("default/api", 1));
+        assert!(waiting.due().is_empty());
+        assert_eq!(waiting.depth(), 1);
+
+        let ready = queue(10, 0, 0);
+        assert!(ready.fail("default/web", 1));
+        assert!(ready.fail("default/api", 2));
+        assert!(ready.fail("default/web", 3));
+        assert_eq!(
+            ready.due(),
+            vec![
+                ("default/api".to_string(), 2),
+                ("default/web".to_string(), 3)
+            ]
+        );
+        assert_eq!(ready.depth(), 0);
+        assert!(ready.due().is_empty());
+    }
+
+    #[test]
+    fn same_key_is_serialized() {
+        let locks = KeyLocks::new();
+        let order = StdMutex::new(vec![]);
+        let (tx, rx) = oneshot::channel::<()>();
+
+        block_on(async {
+            join!(
+                locks.run("default/api-rsa-token", async {
+                    rx.await.unwrap();
+                    order.lock().unwrap().push("first");
+                }),
+                locks.run("default/api-rsa-token", async {
+                    order.lock().unwrap().push("second");
+                }),
+                async { tx.send(()).unwrap() },
+            )
+        });
+        assert_eq!(*order.lock().unwrap(), vec!["first", "second"]);
+        assert!(locks.is_empty());
+    }
+
+    #[test]
+    fn other_keys_are_not_blocked() {
+        let locks = KeyLocks::new();
+        let order = StdMutex::new(vec![]);
+        let (tx, rx) = oneshot::channel::<()>();
+
+        block_on(async {
+            join!(
+                locks.run("default/api-rsa-token", async {
+                    rx.await.unwrap();
+                    order.lock().unwrap().push("api");
+                }),
+                locks.run("default/web-rsa-token", async {
+                    order.lock().unwrap().push("web");
+                }),
+                async { tx.send(()).unwrap() },
+            )
+        });
+        assert_eq!(*order.lock().unwrap(), vec!["web", "api"]);
+        assert!(locks.is_empty());
+    }
+}
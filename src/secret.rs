@@ -1,11 +1,26 @@
 use anyhow::Result;
 use base64::encode;
 use kube::{
-    api::{v1Secret, Api, DeleteParams, PatchParams, PostParams},
+    api::{v1Secret, Api, DeleteParams, OwnerReference, PatchParams, PostParams},
     client::APIClient,
 };
 use serde_json::json;
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, RwLock},
+};
+
+use crate::utils;
+
+/// Resource versions of secrets written by operator
+pub type WriteLog = Arc<RwLock<BTreeSet<String>>>;
+
+/// Check that `version` was written by operator
+///
+/// Every version has a single watch event, so it's forgotten once checked
+pub fn take_write(writes: &WriteLog, version: Option<&str>) -> bool {
+    version.is_some_and(|version| writes.write().unwrap().remove(version))
+}
 
 #[derive(Clone)]
 pub struct RsaSecret {
@@ -16,6 +31,16 @@ pub struct RsaSecret {
     name: String,
 
     fields: BTreeMap<String, String>,
+
+    annotations: BTreeMap<String, String>,
+
+    labels: BTreeMap<String, String>,
+
+    /// Objects owning the secret, it's removed by Kubernetes GC with them
+    owners: Vec<OwnerReference>,
+
+    /// Records resource versions of writes, so their watch events can be told apart
+    writes: Option<WriteLog>,
 }
 
 /// Implements RSA secret management in Kubernetes cluster
@@ -25,40 +50,181 @@ impl RsaSecret {
             api: Api::v1Secret(client).within(&namespace.unwrap_or_else(|| "default".into())),
             name,
             fields: BTreeMap::new(),
+            annotations: BTreeMap::new(),
+            labels: BTreeMap::new(),
+            owners: vec![],
+            writes: None,
         })
     }
 
     /// Set filed named `name` value from `value`
     /// It's overwrite existing value
-    pub async fn add_field(&mut self, name: &str, value: &str) -> Result<&mut Self> {
+    pub async fn add_field(&mut self, name: &str, value: impl AsRef<[u8]>) -> Result<&mut Self> {
         self.fields
             .entry(name.into())
-            .or_insert_with(|| encode(&value));
+            .or_insert_with(|| encode(value.as_ref()));
+        Ok(self)
+    }
+
+    /// Retrive all field values from real Kubernetes secret
+    /// Returns `None` if secret is missing
+    pub async fn data(&self) -> Result<Option<BTreeMap<String, Vec<u8>>>> {
+        Ok(self.fetch().await?.map(|s| {
+            s.data
+                .into_iter()
+                .map(|(name, value)| (name, value.0))
+                .collect()
+        }))
+    }
+
+    /// Set annotation named `name` value from `value`
+    pub async fn annotate(&mut self, name: &str, value: &str) -> Result<&mut Self> {
+        self.annotations.insert(name.into(), value.into());
+        Ok(self)
+    }
+
+    /// Set label named `name` value from `value`
+    pub fn label(mut self, name: &str, value: &str) -> Self {
+        self.labels.insert(name.into(), value.into());
+        self
+    }
+
+    /// Add owner of the secret
+    pub fn own(mut self, owner: OwnerReference) -> Self {
+        self.owners.push(owner);
+        self
+    }
+
+    /// Retrive annotation value from real Kubernetes secret
+    /// Returns `None` if secret or annotation is missing
+    pub async fn annotation(&self, name: &str) -> Result<Option<String>> {
+        Ok(self
+            .fetch()
+            .await?
+            .and_then(|s| s.metadata.annotations.get(name).cloned()))
+    }
+
+    /// Remove annotation from real Kubernetes secret
+    pub async fn remove_annotation(&self, name: &str) -> Result<&Self> {
+        let patch = json!({
+            "metadata": {
+                "annotations": { name: null },
+            },
+        });
+
+        let secret = self
+            .api
+            .patch(
+                &self.name,
+                &PatchParams::default(),
+                serde_json::to_vec(&patch)?,
+            )
+            .await?;
+        self.logged(secret);
         Ok(self)
     }
 
+    /// Remove owner with `uid` from real Kubernetes secret
+    pub async fn disown(&self, uid: &str) -> Result<&Self> {
+        let owners: Vec<OwnerReference> = self
+            .get()
+            .await?
+            .metadata
+            .ownerReferences
+            .into_iter()
+            .filter(|owner| owner.uid != uid)
+            .collect();
+        let patch = json!({
+            "metadata": {
+                "ownerReferences": owners,
+            },
+        });
+
+        let secret = self
+            .api
+            .patch(
+                &self.name,
+                &PatchParams::default(),
+                serde_json::to_vec(&patch)?,
+            )
+            .await?;
+        self.logged(secret);
+        Ok(self)
+    }
+
+    /// Record resource versions of every write in `writes`
+    pub fn log_writes(mut self, writes: WriteLog) -> Self {
+        self.writes = Some(writes);
+        self
+    }
+
+    fn logged(&self, secret: v1Secret) {
+        if let (Some(writes), Some(version)) = (&self.writes, secret.metadata.resourceVersion) {
+            writes.write().unwrap().insert(version);
+        }
+    }
+
     /// Retrive real secret from Kubernetes
     pub async fn get(&self) -> Result<v1Secret> {
         self.api.get(&self.name).await.map_err(|e| e.into())
     }
 
+    /// Retrive real secret from Kubernetes, `None` if it's missing
+    ///
+    /// Other API errors are returned, so they are not mistaken for a missing secret
+    pub async fn fetch(&self) -> Result<Option<v1Secret>> {
+        match self.api.get(&self.name).await {
+            Ok(secret) => Ok(Some(secret)),
+            Err(e) if utils::is_not_found(&e) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Retrive field value from real Kubernetes secret
+    /// Returns `None` if secret or field is missing
+    pub async fn field(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .fetch()
+            .await?
+            .and_then(|s| s.data.get(name).map(|v| v.0.clone())))
+    }
+
     /// Update secret fields...
     pub async fn update(&self) -> Result<&Self> {
-        if self.get().await.is_err() {
+        let existing = self.fetch().await?;
+        if existing.is_none() {
             let _ = self.create().await?;
         }
 
-        let patch = json!({
+        let mut patch = json!({
+            "metadata": {
+                "annotations": serde_json::to_value(self.annotations.clone())?,
+                "labels": serde_json::to_value(self.labels.clone())?,
+            },
             "data": serde_json::to_value(self.fields.clone())?,
         });
+        // Merge patch replaces lists, so new owners are appended to existing ones
+        if !self.owners.is_empty() {
+            let mut owners = existing
+                .map(|s| s.metadata.ownerReferences)
+                .unwrap_or_default();
+            for owner in self.owners.iter() {
+                if !owners.iter().any(|o| o.uid == owner.uid) {
+                    owners.push(owner.clone());
+                }
+            }
+            patch["metadata"]["ownerReferences"] = serde_json::to_value(owners)?;
+        }
 
-        self.api
+        let secret = self
+            .api
             .patch(
                 &self.name,
                 &PatchParams::default(),
                 serde_json::to_vec(&patch)?,
             )
             .await?;
+        self.logged(secret);
 
         Ok(self)
     }
@@ -66,27 +232,62 @@ impl RsaSecret {
     /// Create real Kubernetes secret
     pub async fn create(&self) -> Result<&Self> {
         warn!("Create new secret: {}", self.name);
-        let p = json!({
+        let p = self.manifest(json!({}));
+
+        let secret = self
+            .api
+            .create(&PostParams::default(), serde_json::to_vec(&p)?)
+            .await?;
+        self.logged(secret);
+
+        Ok(self)
+    }
+
+    /// Create real Kubernetes secret with added fields and annotations
+    ///
+    /// Returns `false` if secret already exists, e.g. it was created concurrently
+    pub async fn create_exclusive(&self) -> Result<bool> {
+        warn!("Create new secret: {}", self.name);
+        let mut p = self.manifest(serde_json::to_value(self.fields.clone())?);
+        p["metadata"]["annotations"] = serde_json::to_value(self.annotations.clone())?;
+
+        match self
+            .api
+            .create(&PostParams::default(), serde_json::to_vec(&p)?)
+            .await
+        {
+            Ok(secret) => {
+                self.logged(secret);
+                Ok(true)
+            }
+            Err(e) if utils::is_conflict(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn manifest(&self, data: serde_json::Value) -> serde_json::Value {
+        json!({
             "apiVersion": "v1",
             "kind": "Secret",
             "metadata": {
                 "name": self.name,
+                "labels": self.labels,
+                "ownerReferences": self.owners,
             },
             "type": "Opaque",
-            "data": {},
-        });
-
-        self.api
-            .create(&PostParams::default(), serde_json::to_vec(&p)?)
-            .await?;
-
-        Ok(self)
+            "data": data,
+        })
     }
 
     /// Clean fields in read Kubernetes secret
     pub async fn clean(&self, fields: Vec<String>) -> Result<&Self> {
         info!("Clean secrets for service {}", self.name);
-        let mut data = self.api.get(&self.name).await.map(|s| s.data)?;
+        let mut data = match self.api.get(&self.name).await {
+            Ok(secret) => secret.data,
+            // Already removed, e.g. by Kubernetes GC with its owner
+            Err(e) if utils::is_not_found(&e) => return Ok(self),
+            Err(e) => return Err(e.into()),
+        };
         for field in fields.iter() {
             info!("Remove field '{}' in '{}'", field, &self.name);
             data.remove(field);
@@ -95,25 +296,47 @@ impl RsaSecret {
         if data.is_empty() {
             // NO one key contains - delete secret and return
             warn!("Secret {} is empty... remove it now", self.name);
-            self.api
+            if let Some(secret) = self
+                .api
                 .delete(&self.name, &DeleteParams::default())
-                .await?;
+                .await?
+                .left()
+            {
+                self.logged(secret);
+            }
             return Ok(self);
         }
 
-        let p = json!({
-            "apiVersion": "v1",
-            "kind": "Secret",
-            "metadata": {
-                "name": self.name,
-            },
-            "type": "Opaque",
-            "data": serde_json::to_value(data)?,
-        });
+        // Patch keeps secret metadata, e.g. labels and owners
+        let removed: BTreeMap<String, Option<String>> =
+            fields.into_iter().map(|field| (field, None)).collect();
+        let patch = json!({ "data": removed });
 
-        self.api
-            .replace(&self.name, &PostParams::default(), serde_json::to_vec(&p)?)
+        let secret = self
+            .api
+            .patch(
+                &self.name,
+                &PatchParams::default(),
+                serde_json::to_vec(&patch)?,
+            )
             .await?;
+        self.logged(secret);
         Ok(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_write_is_taken_once() {
+        let writes = WriteLog::default();
+        writes.write().unwrap().insert("42".into());
+
+        assert!(!take_write(&writes, None));
+        assert!(!take_write(&writes, Some("41")));
+        assert!(take_write(&writes, Some("42")));
+        assert!(!take_write(&writes, Some("42")));
+    }
+}
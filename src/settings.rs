@@ -1,5 +1,13 @@
+use crate::{
+    keypair::RotationPolicy,
+    rsa_generator::{Encoding, KeyType},
+    workload::Kind,
+};
 use config::{Config, ConfigError, Environment, File};
-use std::env;
+use std::{env, ops::RangeInclusive};
+
+/// Serial number sizes which keep serials positive and within 20 octets of RFC 5280
+pub const SERIAL_BITS: RangeInclusive<i32> = 1..=159;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
@@ -9,11 +17,34 @@ pub struct Settings {
     pub secrets: Secrets,
     pub volumes: Volumes,
     pub filter: Option<Filter>,
+    pub rotation: Option<Rotation>,
+    /// Watch KeyPair custom resources
+    #[serde(default)]
+    pub key_pairs: bool,
+    /// Watched workload kinds
+    #[serde(default = "Kind::all")]
+    pub workloads: Vec<Kind>,
+    pub leader_election: Option<LeaderElection>,
+    #[serde(default)]
+    pub retry: Retry,
+    pub resync: Option<Resync>,
+    pub webhook: Option<Webhook>,
+    #[serde(default)]
+    pub certificate: Certificate,
+    pub ca: Option<Ca>,
+    #[serde(default)]
+    pub encryption: Encryption,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Rsa {
     pub bits: u32,
+    /// Default key algorithm, can be overridden per deployment
+    #[serde(default)]
+    pub key_type: KeyType,
+    /// Default private key encodings, can be overridden per deployment
+    #[serde(default = "Encoding::defaults")]
+    pub encodings: Vec<Encoding>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -34,6 +65,267 @@ pub struct Volume {
     pub path: String,
 }
 
+/// Scheduled key rotation policy, all durations are in seconds
+#[derive(Debug, Deserialize, Clone)]
+pub struct Rotation {
+    /// Key age after which it's regenerated
+    pub max_age: i64,
+    /// How long previous public key stays published after rotation
+    pub overlap: i64,
+    /// How often keys age is checked
+    #[serde(default = "default_rotation_interval")]
+    pub interval: u64,
+}
+
+impl Rotation {
+    /// Rotation policy of keys, the same as `KeyPair` resources declare
+    pub fn policy(&self) -> RotationPolicy {
+        RotationPolicy {
+            max_age: self.max_age,
+            overlap: self.overlap,
+        }
+    }
+}
+
+fn default_rotation_interval() -> u64 {
+    60
+}
+
+/// Periodic comparison of managed workloads with their secrets and mounts
+#[derive(Debug, Deserialize, Clone)]
+pub struct Resync {
+    /// Seconds between full resyncs
+    pub interval: u64,
+}
+
+/// Mutating admission webhook injecting volumes into pods instead of patching workloads
+#[derive(Debug, Deserialize, Clone)]
+pub struct Webhook {
+    /// HTTPS port of webhook server
+    #[serde(default = "default_webhook_port")]
+    pub port: u16,
+    /// Path to PEM encoded TLS certificate
+    pub cert: String,
+    /// Path to PEM encoded TLS private key
+    pub key: String,
+}
+
+fn default_webhook_port() -> u16 {
+    8443
+}
+
+/// X.509 certificate issued for every service key
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Certificate {
+    /// Days certificate is valid for
+    pub validity_days: u32,
+    /// Size of random serial number in bits, within `SERIAL_BITS`
+    pub serial_bits: i32,
+    /// Subject fields besides common name, which is the service name
+    pub subject: Subject,
+    /// Cluster DNS suffix of service names in subject alternative names
+    pub cluster_domain: String,
+    pub key_usage: Vec<KeyUsage>,
+    pub extended_key_usage: Vec<ExtendedKeyUsage>,
+    /// Part of certificate lifetime after which it's renewed
+    pub renew_fraction: f64,
+    /// Seconds between certificate expiry checks
+    pub renew_interval: u64,
+}
+
+impl Default for Certificate {
+    fn default() -> Self {
+        Certificate {
+            validity_days: 365,
+            serial_bits: 128,
+            subject: Subject::default(),
+            cluster_domain: "cluster.local".into(),
+            key_usage: vec![KeyUsage::DigitalSignature, KeyUsage::KeyEncipherment],
+            extended_key_usage: vec![ExtendedKeyUsage::ServerAuth, ExtendedKeyUsage::ClientAuth],
+            renew_fraction: 2.0 / 3.0,
+            renew_interval: 3600,
+        }
+    }
+}
+
+impl Certificate {
+    /// Configured key usages which keys of `key_type` are capable of
+    pub fn key_usage(&self, key_type: KeyType) -> Vec<KeyUsage> {
+        self.key_usage
+            .iter()
+            .copied()
+            .filter(|usage| usage.supports(key_type))
+            .collect()
+    }
+
+    /// DNS names of service in cluster, from short name to fully qualified one
+    pub fn dns_names(&self, service_name: &str, namespace: &str) -> Vec<String> {
+        vec![
+            service_name.to_string(),
+            format!("{}.{}", service_name, namespace),
+            format!("{}.{}.svc", service_name, namespace),
+            format!("{}.{}.svc.{}", service_name, namespace, self.cluster_domain),
+        ]
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Subject {
+    pub organization: Option<String>,
+    pub organizational_unit: Option<String>,
+    pub country: Option<String>,
+    pub state: Option<String>,
+    pub locality: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum KeyUsage {
+    DigitalSignature,
+    NonRepudiation,
+    KeyEncipherment,
+    DataEncipherment,
+    KeyAgreement,
+}
+
+impl KeyUsage {
+    /// Check that keys of `key_type` can be used this way, e.g. only RSA keys encrypt
+    pub fn supports(self, key_type: KeyType) -> bool {
+        match self {
+            KeyUsage::KeyEncipherment | KeyUsage::DataEncipherment => key_type == KeyType::Rsa,
+            KeyUsage::KeyAgreement => {
+                matches!(key_type, KeyType::P256 | KeyType::P384 | KeyType::P521)
+            }
+            KeyUsage::DigitalSignature | KeyUsage::NonRepudiation => true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ExtendedKeyUsage {
+    ServerAuth,
+    ClientAuth,
+    CodeSigning,
+    EmailProtection,
+}
+
+/// Passphrase encryption of private keys at rest
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Encryption {
+    /// Encrypt keys of all services, otherwise only of annotated ones
+    pub enabled: bool,
+    /// Random bytes in generated passphrase
+    pub passphrase_length: usize,
+}
+
+impl Default for Encryption {
+    fn default() -> Self {
+        Encryption {
+            enabled: false,
+            passphrase_length: 32,
+        }
+    }
+}
+
+/// Operator certificate authority signing service certificates
+#[derive(Debug, Deserialize, Clone)]
+pub struct Ca {
+    /// Secret with `tls.crt` and `tls.key` of CA, generated when missing
+    pub secret_name: String,
+    /// Namespace of CA secret, operator own namespace by default
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    #[serde(default = "default_ca_common_name")]
+    pub common_name: String,
+    #[serde(default)]
+    pub key_type: KeyType,
+    #[serde(default = "default_ca_bits")]
+    pub bits: u32,
+    /// Days generated CA is valid for
+    #[serde(default = "default_ca_validity_days")]
+    pub validity_days: u32,
+    /// Days before CA expiry when it's replaced by new one
+    #[serde(default = "default_ca_rollover_days")]
+    pub rollover_days: u32,
+    /// Seconds new root is only trusted before it signs certificates, so bundle reaches all services
+    #[serde(default = "default_ca_propagation_delay")]
+    pub propagation_delay: u64,
+    /// Seconds between CA expiry checks
+    #[serde(default = "default_ca_interval")]
+    pub interval: u64,
+}
+
+fn default_ca_common_name() -> String {
+    "key-generator-ca".into()
+}
+
+fn default_ca_bits() -> u32 {
+    4096
+}
+
+fn default_ca_validity_days() -> u32 {
+    3650
+}
+
+fn default_ca_rollover_days() -> u32 {
+    90
+}
+
+fn default_ca_propagation_delay() -> u64 {
+    86400
+}
+
+fn default_ca_interval() -> u64 {
+    3600
+}
+
+/// Retry policy of failed events, delays are in seconds
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Retry {
+    /// Retries count after which event is dropped
+    pub max_retries: u32,
+    /// Delay before first retry, doubled after every failure
+    pub base_delay: u64,
+    /// Upper bound of retry delay
+    pub max_delay: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            max_retries: 10,
+            base_delay: 1,
+            max_delay: 300,
+        }
+    }
+}
+
+/// Lease based leader election for running several replicas
+#[derive(Debug, Deserialize, Clone)]
+pub struct LeaderElection {
+    /// Name of Lease object
+    pub lease_name: String,
+    /// Namespace of Lease object, operator own namespace by default
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    /// Seconds after which lease of silent leader can be taken over
+    #[serde(default = "default_lease_duration")]
+    pub lease_duration: i64,
+}
+
+fn default_namespace() -> String {
+    env::var("POD_NAMESPACE").unwrap_or_else(|_| "default".into())
+}
+
+fn default_lease_duration() -> i64 {
+    15
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Filter {
     pub namespaces: Vec<String>,
@@ -44,6 +336,66 @@ impl Settings {
         let mut s = Config::new();
         s.merge(File::with_name(path).required(true))?;
         s.merge(Environment::with_prefix("app"))?;
-        s.try_into()
+        let settings: Settings = s.try_into()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Reject values the operator can't work with
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !SERIAL_BITS.contains(&self.certificate.serial_bits) {
+            return Err(ConfigError::Message(format!(
+                "certificate.serial_bits must be within {}..={}",
+                SERIAL_BITS.start(),
+                SERIAL_BITS.end()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Build annotation name with the same prefix as service annotation
+    pub fn annotation_key(&self, name: &str) -> String {
+        match self.annotation.rfind('/') {
+            Some(idx) => format!("{}/{}", &self.annotation[..idx], name),
+            None => name.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_usage_follows_key_type() {
+        let config = Certificate {
+            key_usage: vec![
+                KeyUsage::DigitalSignature,
+                KeyUsage::KeyEncipherment,
+                KeyUsage::KeyAgreement,
+            ],
+            ..Certificate::default()
+        };
+        assert_eq!(
+            config.key_usage(KeyType::Rsa),
+            vec![KeyUsage::DigitalSignature, KeyUsage::KeyEncipherment]
+        );
+        assert_eq!(
+            config.key_usage(KeyType::P384),
+            vec![KeyUsage::DigitalSignature, KeyUsage::KeyAgreement]
+        );
+        assert_eq!(
+            config.key_usage(KeyType::Ed25519),
+            vec![KeyUsage::DigitalSignature]
+        );
+    }
+
+    #[test]
+    fn serial_bits_are_bounded() {
+        let mut settings = Settings::new("config/default").unwrap();
+        for (serial_bits, valid) in [(0, false), (1, true), (159, true), (160, false)] {
+            settings.certificate.serial_bits = serial_bits;
+            assert_eq!(settings.validate().is_ok(), valid, "{}", serial_bits);
+        }
     }
 }
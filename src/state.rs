@@ -1,36 +1,78 @@
 use crate::*;
 use anyhow::Result;
-use chrono::prelude::*;
+use chrono::{prelude::*, Duration};
+use futures::Future;
 use futures::StreamExt;
-use k8s_openapi::api::apps::v1::{DeploymentSpec, DeploymentStatus};
+use k8s_openapi::ByteString;
+use keypair::KeyPair;
 use kube::{
-    api::{Api, Informer, Object, WatchEvent},
+    api::{
+        v1Secret, Api, Informer, ListParams, Object, ObjectMeta, OwnerReference, PatchParams,
+        WatchEvent,
+    },
     client::APIClient,
     config::Configuration,
 };
 use prometheus::{
+    core::Collector,
     default_registry,
     proto::MetricFamily,
     {IntCounter, IntCounterVec, IntGauge, IntGaugeVec},
 };
+use queue::{KeyLocks, RetryQueue};
+use rsa_generator::{Encoding, Generator, Issuer, KeyType};
+use serde_json::json;
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     env,
     sync::{Arc, RwLock},
 };
-
-pub type Deployment = Object<DeploymentSpec, DeploymentStatus>;
+use store::Passphrase;
+use workload::{Kind, Workload};
 
 /// Metrics exposed to /metrics
 #[derive(Clone)]
 pub struct Metrics {
     pub handled_events: IntCounter,
+    pub rotated_keys: IntCounter,
+    pub leader: IntGauge,
+    pub retry_queue_depth: IntGauge,
+    pub retries: IntCounter,
+    pub dropped_events: IntCounter,
+    pub drift_repairs: IntCounter,
+    pub renewed_certificates: IntCounter,
+    pub certificate_expiry: IntGaugeVec,
 }
 
 impl Metrics {
     fn new() -> Self {
         Metrics {
             handled_events: register_int_counter!("handled_events", "handled events").unwrap(),
+            rotated_keys: register_int_counter!("rotated_keys", "rotated keys").unwrap(),
+            leader: register_int_gauge!("leader", "whether instance is leader").unwrap(),
+            retry_queue_depth: register_int_gauge!(
+                "retry_queue_depth",
+                "failed events waiting for retry"
+            )
+            .unwrap(),
+            retries: register_int_counter!("retries", "retried events").unwrap(),
+            dropped_events: register_int_counter!(
+                "dropped_events",
+                "events dropped after exhausted retries"
+            )
+            .unwrap(),
+            drift_repairs: register_int_counter!("drift_repairs", "repaired drifts").unwrap(),
+            renewed_certificates: register_int_counter!(
+                "renewed_certificates",
+                "certificates renewed before expiry"
+            )
+            .unwrap(),
+            certificate_expiry: register_int_gauge_vec!(
+                "certificate_expiry_timestamp_seconds",
+                "expiry time of service certificate",
+                &["namespace", "service"]
+            )
+            .unwrap(),
         }
     }
 }
@@ -40,14 +82,115 @@ impl Metrics {
 pub struct State {
     #[serde(deserialize_with = "from_ts")]
     pub last_event: DateTime<Utc>,
+    /// Only leader handles events, followers just serve http
+    pub leader: bool,
+    /// Leader reconciled changes made before it took over, so events can be polled
+    pub synced: bool,
 }
 
 impl State {
     fn new() -> Self {
         State {
             last_event: Utc::now(),
+            leader: false,
+            synced: false,
+        }
+    }
+}
+
+/// Watch event of any handled object, kept for retry
+#[derive(Clone)]
+enum Event {
+    Workload(Kind, WatchEvent<Workload>),
+    KeyPair(WatchEvent<KeyPair>),
+    Secret(WatchEvent<v1Secret>),
+}
+
+impl Event {
+    fn metadata(&self) -> Option<&ObjectMeta> {
+        match self {
+            Event::Workload(_, WatchEvent::Added(o))
+            | Event::Workload(_, WatchEvent::Modified(o))
+            | Event::Workload(_, WatchEvent::Deleted(o)) => Some(&o.metadata),
+            Event::KeyPair(WatchEvent::Added(o))
+            | Event::KeyPair(WatchEvent::Modified(o))
+            | Event::KeyPair(WatchEvent::Deleted(o)) => Some(&o.metadata),
+            Event::Secret(WatchEvent::Added(o))
+            | Event::Secret(WatchEvent::Modified(o))
+            | Event::Secret(WatchEvent::Deleted(o)) => Some(&o.metadata),
+            _ => None,
         }
     }
+
+    /// Retry queue key, unique per object
+    fn key(&self) -> Option<String> {
+        let kind = match self {
+            Event::Workload(kind, _) => kind.to_string(),
+            Event::KeyPair(_) => "KeyPair".into(),
+            Event::Secret(_) => "Secret".into(),
+        };
+        let metadata = self.metadata()?;
+        Some(format!(
+            "{}/{}/{}",
+            kind,
+            metadata
+                .namespace
+                .clone()
+                .unwrap_or_else(|| "default".into()),
+            metadata.name
+        ))
+    }
+}
+
+/// Keys owner, resolved from annotated workload or KeyPair
+struct Target {
+    /// Secrets storage for the owner
+    store: Store,
+    namespace: Option<String>,
+    /// Service name used for public key entries
+    name: String,
+    key_type: KeyType,
+    bits: u32,
+    rotation: Option<keypair::RotationPolicy>,
+    certificate: settings::Certificate,
+}
+
+impl Target {
+    fn secret_name(&self) -> String {
+        self.store.secret_name(&self.name)
+    }
+
+    async fn has_keys(&self) -> Result<bool> {
+        self.store
+            .has_keys(self.namespace.clone(), self.name.clone(), self.key_type)
+            .await
+    }
+
+    /// Generate keys with certificate signed by `issuer`
+    fn generate(&self, issuer: Option<&Issuer>) -> Result<Generator> {
+        info!("Generate {} keys for {}", self.key_type, self.name);
+        let namespace = self.namespace.as_deref().unwrap_or("default");
+        Ok(Generator::new(
+            self.key_type,
+            self.bits,
+            self.name.clone(),
+            namespace,
+            &self.certificate,
+            issuer,
+        )?)
+    }
+
+    /// New certificate for existing private key
+    fn reissue(&self, private_key: &[u8], issuer: Option<&Issuer>) -> Result<Vec<u8>> {
+        let namespace = self.namespace.as_deref().unwrap_or("default");
+        Ok(Generator::reissue(
+            private_key,
+            &self.name,
+            namespace,
+            &self.certificate,
+            issuer,
+        )?)
+    }
 }
 
 /// User state for Actix and controller
@@ -55,33 +198,68 @@ impl State {
 pub struct Controller {
     /// A controller settings
     config: Settings,
-    /// An informer for Deployment
-    info: Informer<Deployment>,
+    /// Informers for every watched workload kind
+    informers: Vec<(Kind, Informer<Workload>)>,
+    /// An informer for KeyPair, if custom resources are enabled
+    key_pairs: Option<Informer<KeyPair>>,
+    /// An informer for operator-managed secrets
+    secrets: Informer<v1Secret>,
     /// In memory state
     state: Arc<RwLock<State>>,
     /// Various Prometheus metrics
     metrics: Arc<RwLock<Metrics>>,
-    /// A kube client for performing cluster actions on Deployment events
+    /// A kube client for performing cluster actions on workload events
     client: APIClient,
     /// A secrets storage manager
     store: Store,
+    /// Failed events waiting for retry
+    retries: RetryQueue<Event>,
+    /// Locks of private secrets, keys are written by events, retries and periodic loops
+    locks: KeyLocks,
+    /// Certificate authority signing service certificates
+    ca: Option<ca::Authority>,
 }
 
-/// Controller that wathes Deployments
+/// Controller that wathes workloads
 impl Controller {
     async fn new(client: APIClient, config: Settings) -> Result<Self> {
-        let resource = Api::v1Deployment(client.clone());
-        let info = Informer::new(resource).timeout(15).init().await?;
+        let mut informers = vec![];
+        for kind in config.workloads.clone() {
+            let resource = kind.api(client.clone());
+            informers.push((kind, Informer::new(resource).timeout(15).init().await?));
+        }
+        let key_pairs = if config.key_pairs {
+            let resource = keypair::api(client.clone());
+            Some(Informer::new(resource).timeout(15).init().await?)
+        } else {
+            None
+        };
+        let resource = Api::v1Secret(client.clone());
+        let secrets = Informer::new(resource)
+            .labels(&format!("{}=true", config.annotation_key("managed")))
+            .timeout(15)
+            .init()
+            .await?;
         let metrics = Arc::new(RwLock::new(Metrics::new()));
         let state = Arc::new(RwLock::new(State::new()));
         let store = Store::new(client.clone(), config.clone()).await?;
+        let retries = RetryQueue::new(config.retry.clone());
+        let ca = config
+            .ca
+            .clone()
+            .map(|ca| ca::Authority::new(client.clone(), ca, &config));
         Ok(Controller {
+            ca,
             config,
-            info,
+            informers,
+            key_pairs,
+            secrets,
             metrics,
             state,
             client,
             store,
+            retries,
+            locks: KeyLocks::new(),
         })
     }
 
@@ -97,75 +275,623 @@ impl Controller {
         Ok(res)
     }
 
+    /// JWKS document getter
+    pub async fn jwks(&self) -> Result<serde_json::Value> {
+        self.store.jwks().await
+    }
+
     /// Internal poll for internal thread
-    async fn poll(&self) -> Result<()> {
-        let mut deploys = self.info.poll().await?.boxed();
-        while let Some(event) = deploys.next().await {
-            match self.handle_event(event?).await {
-                Ok(_) => {}
-                Err(e) => warn!("Cannot process service: {}", e),
-            };
+    async fn poll(&self, kind: Kind, informer: &Informer<Workload>) -> Result<()> {
+        let mut workloads = informer.poll().await?.boxed();
+        while let Some(event) = workloads.next().await {
+            if !self.is_synced() {
+                break;
+            }
+            self.handle(Event::Workload(kind, event?)).await;
+        }
+        Ok(())
+    }
+
+    /// Internal poll of operator-managed secrets informer
+    async fn poll_secrets(&self) -> Result<()> {
+        let mut secrets = self.secrets.poll().await?.boxed();
+        while let Some(event) = secrets.next().await {
+            if !self.is_synced() {
+                break;
+            }
+            self.handle(Event::Secret(event?)).await;
         }
         Ok(())
     }
 
-    /// Handle deployment events and make some things for some kinds
-    async fn handle_event(&self, ev: WatchEvent<Deployment>) -> Result<()> {
+    /// Internal poll of KeyPair informer
+    async fn poll_key_pairs(&self, informer: &Informer<KeyPair>) -> Result<()> {
+        let mut key_pairs = informer.poll().await?.boxed();
+        while let Some(event) = key_pairs.next().await {
+            if !self.is_synced() {
+                break;
+            }
+            self.handle(Event::KeyPair(event?)).await;
+        }
+        Ok(())
+    }
+
+    /// Process event, failed one is scheduled for retry
+    async fn handle(&self, event: Event) {
+        let key = match event.key() {
+            Some(key) => key,
+            None => {
+                debug!("Unsupported event");
+                return;
+            }
+        };
+        if self.check_namespace(event.metadata().unwrap()).is_err() {
+            return;
+        }
+
+        let res = match event.clone() {
+            Event::Workload(kind, ev) => self.handle_event(kind, ev).await,
+            Event::KeyPair(ev) => self.handle_key_pair_event(ev).await,
+            Event::Secret(ev) => self.handle_secret_event(ev).await,
+        };
+        match res {
+            Ok(_) => self.retries.forget(&key),
+            Err(e) => {
+                warn!("Cannot process {}: {}", key, e);
+                if !self.retries.fail(&key, event) {
+                    warn!(
+                        "Give up on {} after {} retries",
+                        key, self.config.retry.max_retries
+                    );
+                    self.metrics.write().unwrap().dropped_events.inc();
+                }
+            }
+        }
+        self.metrics
+            .write()
+            .unwrap()
+            .retry_queue_depth
+            .set(self.retries.depth() as i64);
+    }
+
+    /// Process failed events which are due for retry
+    async fn retry(&self) {
+        for (key, event) in self.retries.due() {
+            debug!("Retry {}", key);
+            self.metrics.write().unwrap().retries.inc();
+            self.handle(event).await;
+        }
+    }
+
+    /// Run `work` on keys in private secret exclusively, concurrent work on it waits
+    async fn exclusive<T>(
+        &self,
+        namespace: &Option<String>,
+        secret_name: &str,
+        work: impl Future<Output = T>,
+    ) -> T {
+        let key = format!(
+            "{}/{}",
+            namespace.as_deref().unwrap_or("default"),
+            secret_name
+        );
+        self.locks.run(&key, work).await
+    }
+
+    fn is_leader(&self) -> bool {
+        self.state.read().unwrap().leader
+    }
+
+    fn set_leader(&self, leader: bool) {
+        if self.is_leader() != leader {
+            info!("Leadership changed, leader: {}", leader);
+        }
+        {
+            let mut state = self.state.write().unwrap();
+            state.leader = leader;
+            if !leader {
+                state.synced = false;
+            }
+        }
+        let metrics = self.metrics.write().unwrap();
+        metrics.leader.set(leader as i64);
+        // Only leader watches certificates, so follower series would go stale
+        if !leader {
+            metrics.certificate_expiry.reset();
+        }
+    }
+
+    fn is_synced(&self) -> bool {
+        let state = self.state.read().unwrap();
+        state.leader && state.synced
+    }
+
+    /// Wait until instance is leader which reconciled changes made before it took over
+    async fn await_sync(&self) {
+        while !self.is_synced() {
+            tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+        }
+    }
+
+    /// Reconcile everything changed while this instance was not leading
+    ///
+    /// Informers restart from the latest version and skip events of the handover window,
+    /// so all objects are swept and resynced before polling resumes.
+    async fn take_over(&self) -> Result<()> {
+        info!("Take over leadership... reconcile all objects");
+        for (_, informer) in &self.informers {
+            informer.reset().await?;
+        }
+        if let Some(informer) = &self.key_pairs {
+            informer.reset().await?;
+        }
+        self.secrets.reset().await?;
+        self.store.forget_writes();
+
+        if let Err(e) = self.sweep().await {
+            warn!("Cannot sweep orphaned secrets: {}", e);
+        }
+        self.resync().await?;
+
+        let mut state = self.state.write().unwrap();
+        // Leadership may be lost meanwhile, next leadership starts over
+        state.synced = state.leader;
+        Ok(())
+    }
+
+    /// Keep lease renewed and leadership state up to date
+    async fn elect(&self, elector: leader::Elector) {
+        loop {
+            match elector.try_acquire().await {
+                Ok(leader) => self.set_leader(leader),
+                Err(e) => {
+                    warn!("Cannot acquire lease: {}", e);
+                    self.set_leader(false);
+                }
+            }
+            tokio::time::delay_for(elector.renew_interval()).await;
+        }
+    }
+
+    /// Handle workload events and make some things for some kinds
+    async fn handle_event(&self, kind: Kind, ev: WatchEvent<Workload>) -> Result<()> {
         match ev {
-            WatchEvent::Added(deploy) => {
-                info!("Deployment {:?} added...", deploy.metadata.name);
-                info!("Check deployment filtering...");
+            WatchEvent::Added(workload) => {
+                info!("{} {:?} added...", kind, workload.metadata.name);
+                if self.is_service(kind, &workload) {
+                    self.handle_apply(kind, workload).await?;
+                }
+            }
+            WatchEvent::Modified(workload) => {
+                debug!("{} {:?} modified...", kind, workload.metadata.name);
+                if self.is_service(kind, &workload) {
+                    self.handle_apply(kind, workload).await?;
+                } else if self.was_service(kind, &workload) {
+                    self.handle_opt_out(kind, workload).await?;
+                }
+            }
+            WatchEvent::Deleted(workload) => {
+                info!("{} {:?} deleted...", kind, workload.metadata.name);
 
-                let deploy_namespace = deploy
-                    .metadata
-                    .namespace
-                    .clone()
-                    .unwrap_or_else(|| "default".to_string());
-                let deploy_name = deploy.metadata.name.clone();
-                info!(
-                    "Deployment: {} with namespace: {}",
-                    deploy_name, &deploy_namespace
-                );
-                if let Some(filter) = self.config.filter.clone() {
-                    if !filter.namespaces.is_empty()
-                        && !filter.namespaces.contains(&deploy_namespace)
-                    {
-                        info!("Skip this deployment from different namespace");
-                        return Err(anyhow::format_err!(
-                            "Skip this deployment from different namespace"
-                        ));
+                if !self.is_service(kind, &workload) {
+                    debug!("Not a service... skipping");
+                } else if self.get_key_pair_ref(&workload).is_some() {
+                    debug!("Keys are owned by KeyPair... skipping");
+                } else {
+                    let service_name = self.get_service_name(workload.clone())?;
+                    let namespace = workload.metadata.namespace.clone();
+                    let secret_name = self.store.secret_name(&service_name);
+                    self.exclusive(&namespace, &secret_name, async {
+                        if self.is_shared(&workload, &service_name).await? {
+                            debug!("Keys are used by other workloads... skipping");
+                            return Ok(());
+                        }
+                        self.store
+                            .handle_delete(namespace.clone(), service_name.clone())
+                            .await
+                    })
+                    .await?;
+                }
+
+                self.metrics.write().unwrap().handled_events.inc();
+            }
+            _ => debug!("Unsupported event"),
+        }
+
+        self.state.write().unwrap().last_event = Utc::now();
+        Ok(())
+    }
+
+    /// Provision keys and mounts for annotated workload
+    async fn handle_apply(&self, kind: Kind, workload: Workload) -> Result<()> {
+        let trigger = self.get_rotation_trigger(&workload);
+        self.apply(kind, workload, trigger).await
+    }
+
+    /// Provision keys and mounts, rotating keys if `trigger` is set
+    async fn apply(&self, kind: Kind, workload: Workload, trigger: Option<String>) -> Result<()> {
+        self.check_namespace(&workload.metadata)?;
+
+        if let Some(key_pair) = self.get_key_pair_ref(&workload) {
+            return self.handle_key_pair_ref(kind, workload, key_pair).await;
+        }
+
+        info!("Fetch service name...");
+        let target = self.workload_target(kind, &workload)?;
+        self.check_conflict(kind, &workload, &target).await?;
+
+        self.exclusive(&target.namespace, &target.secret_name(), async {
+            match trigger {
+                Some(trigger) if target.has_keys().await? => {
+                    let overlap_until = target
+                        .store
+                        .overlap_until(target.namespace.clone(), target.name.clone())
+                        .await?;
+                    // Trigger stays unhandled and is applied by rotation after previous key retires
+                    if let Some(overlap_until) = overlap_until {
+                        info!(
+                            "Rotation requested for {} by {} deferred until {}",
+                            target.name,
+                            trigger,
+                            overlap_until.to_rfc3339()
+                        );
+                        return self.ensure_keys(&target).await;
                     }
+                    info!("Rotation requested for {} by {}", target.name, trigger);
+                    self.rotate_keys(&target).await?;
+                    self.mark_rotated(kind, &workload, trigger).await
                 }
+                // Freshly generated keys satisfy the trigger, so it's not replayed on them
+                Some(trigger) => {
+                    self.ensure_keys(&target).await?;
+                    self.mark_rotated(kind, &workload, trigger).await
+                }
+                None => self.ensure_keys(&target).await,
+            }
+        })
+        .await?;
+
+        // Secret of KeyPair referenced before is replaced by own one
+        let released: Vec<String> = self.mounted_key_pair(&workload).into_iter().collect();
+        if self.mounts_workloads() {
+            info!("Initialize mounter...");
+            let mounter = mounter::Mounter::new(
+                self.client.clone(),
+                kind,
+                workload.clone(),
+                self.config.clone(),
+            )
+            .await?
+            .with_secret_name(utils::secret_name(target.name.clone()))
+            .with_released(released.clone());
+            info!("Mount...");
+            mounter.mount().await?;
+        } else if !self.config.volumes.mount {
+            // Volumes mounted before mounting was turned off reference secrets which may be gone
+            mounter::Mounter::new(
+                self.client.clone(),
+                kind,
+                workload.clone(),
+                self.config.clone(),
+            )
+            .await?
+            .with_secret_name(utils::secret_name(target.name.clone()))
+            .with_released(released.clone())
+            .unmount()
+            .await?;
+        }
+        if !released.is_empty() && (self.mounts_workloads() || !self.config.volumes.mount) {
+            self.record_key_pair(kind, &workload, None).await?;
+        }
+        self.mark_provisioned(kind, &workload).await?;
 
-                info!("Fetch service name...");
-                let service_name = self.get_service_name(deploy.clone())?;
+        info!("Write to metrics...");
+        self.metrics.write().unwrap().handled_events.inc();
+        Ok(())
+    }
 
-                let generator = rsa_generator::Generator::new(self.config.rsa.bits, service_name)?;
-                self.store
-                    .handle_add(deploy.metadata.namespace.clone(), generator)
+    /// Reject workload named as service whose keys already belong to workload of another kind
+    ///
+    /// Explicitly named services are shared on purpose, e.g. by a canary deployment
+    async fn check_conflict(&self, kind: Kind, workload: &Workload, target: &Target) -> Result<()> {
+        if workload.metadata.annotations.get(&self.config.annotation) != Some(&"true".into()) {
+            return Ok(());
+        }
+        let owners = target
+            .store
+            .owners(target.namespace.clone(), target.name.clone())
+            .await?;
+        match workload::conflicting_owner(kind, workload, &owners) {
+            Some(owner) => Err(anyhow::format_err!(
+                "{} {} and {} {} would share keys in {}, set service name of one of them explicitly",
+                kind,
+                workload.metadata.name,
+                owner.kind,
+                owner.name,
+                target.secret_name()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Volumes are mounted by patching workloads unless webhook injects them into pods
+    fn mounts_workloads(&self) -> bool {
+        self.config.volumes.mount && self.config.webhook.is_none()
+    }
+
+    /// JSON patch injecting operator volumes into pod created by managed workload
+    pub async fn mutate_pod(
+        &self,
+        namespace: &str,
+        pod: &serde_json::Value,
+    ) -> Result<Option<serde_json::Value>> {
+        if !self.config.volumes.mount {
+            return Ok(None);
+        }
+
+        let metadata: ObjectMeta = serde_json::from_value(pod["metadata"].clone())?;
+        let (kind, workload) = match self
+            .pod_workload(namespace, metadata.ownerReferences)
+            .await?
+        {
+            Some(owner) => owner,
+            None => return Ok(None),
+        };
+        if self.check_namespace(&workload.metadata).is_err() {
+            return Ok(None);
+        }
+
+        let secret_name = match self.get_key_pair_ref(&workload) {
+            Some(key_pair) => {
+                let key_pair = keypair::api(self.client.clone())
+                    .within(namespace)
+                    .get(&key_pair)
+                    .await?;
+                self.key_pair_secret(&key_pair)?
+            }
+            None => utils::secret_name(self.get_service_name(workload.clone())?),
+        };
+        info!(
+            "Inject volumes into pod of {} {}",
+            kind, workload.metadata.name
+        );
+        let patch =
+            match mounter::Mounter::new(self.client.clone(), kind, workload, self.config.clone())
+                .await?
+                .with_secret_name(secret_name)
+                .pod_spec_patch(&pod["spec"])?
+            {
+                Some(patch) => patch,
+                None => return Ok(None),
+            };
+
+        let mut mutated = pod.clone();
+        json_patch::merge(&mut mutated, &json!({ "spec": patch }));
+        Ok(Some(serde_json::to_value(json_patch::diff(pod, &mutated))?))
+    }
+
+    /// Managed workload which created pod, ReplicaSets and Jobs of CronJobs are followed up
+    async fn pod_workload(
+        &self,
+        namespace: &str,
+        owners: Vec<OwnerReference>,
+    ) -> Result<Option<(Kind, Workload)>> {
+        let watched: Vec<Kind> = self.informers.iter().map(|(kind, _)| *kind).collect();
+        workload::pod_owner(
+            owners,
+            &watched,
+            |kind, workload| self.is_service(kind, workload),
+            |kind, name| {
+                let api = match kind {
+                    Some(kind) => kind.api(self.client.clone()),
+                    None => workload::replica_sets(self.client.clone()),
+                };
+                async move { Ok(api.within(namespace).get(&name).await?) }
+            },
+        )
+        .await
+    }
+
+    /// Mount secrets of referenced KeyPair to workload
+    async fn handle_key_pair_ref(
+        &self,
+        kind: Kind,
+        workload: Workload,
+        key_pair: String,
+    ) -> Result<()> {
+        let namespace = workload
+            .metadata
+            .namespace
+            .clone()
+            .unwrap_or_else(|| "default".into());
+        let key_pair = keypair::api(self.client.clone())
+            .within(&namespace)
+            .get(&key_pair)
+            .await?;
+
+        let secret_name = self.key_pair_secret(&key_pair)?;
+        let mounted = self.mounted_key_pair(&workload);
+        // Secret of KeyPair referenced before is replaced in the same patch
+        let released = mounted
+            .iter()
+            .filter(|name| **name != secret_name)
+            .cloned()
+            .collect();
+        let mounter = mounter::Mounter::new(
+            self.client.clone(),
+            kind,
+            workload.clone(),
+            self.config.clone(),
+        )
+        .await?
+        .with_secret_name(secret_name.clone())
+        .with_released(released);
+        if self.mounts_workloads() {
+            info!("Mount KeyPair {} secrets...", key_pair.metadata.name);
+            mounter.mount().await?;
+            if mounted.as_ref() != Some(&secret_name) {
+                self.record_key_pair(kind, &workload, Some(secret_name))
                     .await?;
+            }
+        } else if !self.config.volumes.mount {
+            mounter.unmount().await?;
+            if mounted.is_some() {
+                self.record_key_pair(kind, &workload, None).await?;
+            }
+        }
 
-                if self.config.volumes.mount {
-                    info!("Initialize mounter...");
-                    let mounter =
-                        mounter::Mounter::new(self.client.clone(), deploy, self.config.clone())
-                            .await?;
-                    info!("Mount...");
-                    mounter.mount().await?;
+        self.metrics.write().unwrap().handled_events.inc();
+        Ok(())
+    }
+
+    /// Remove keys and mounts from workload which lost the annotation
+    async fn handle_opt_out(&self, kind: Kind, workload: Workload) -> Result<()> {
+        if self.check_namespace(&workload.metadata).is_err()
+            || workload::is_spawned(kind, &workload)
+        {
+            return Ok(());
+        }
+
+        // Annotation is gone, so service names are taken from owned secrets
+        let namespace = workload.metadata.namespace.clone();
+        let uid = workload.metadata.uid.clone().unwrap_or_default();
+        let mut services: Vec<(String, String)> = self
+            .store
+            .owned_secrets(namespace.clone(), &uid)
+            .await?
+            .into_iter()
+            .filter_map(|secret| {
+                let service_name = self.store.service_name(&secret)?;
+                Some((secret.metadata.name, service_name))
+            })
+            .collect();
+        // Secrets created before owners were set are named after workload
+        let name = workload.metadata.name.clone();
+        if services.is_empty()
+            && self
+                .store
+                .has_secret(namespace.clone(), name.clone())
+                .await?
+        {
+            services.push((utils::secret_name(name.clone()), name));
+        }
+
+        // Volumes are removed even if mounting is off, their secrets are about to be deleted
+        // or belong to KeyPair which is not referenced anymore
+        let key_pair_secret = self.mounted_key_pair(&workload);
+        let released: Vec<String> = services
+            .iter()
+            .map(|(secret_name, _)| secret_name.clone())
+            .chain(key_pair_secret.clone())
+            .collect();
+        if !released.is_empty() {
+            mounter::Mounter::new(
+                self.client.clone(),
+                kind,
+                workload.clone(),
+                self.config.clone(),
+            )
+            .await?
+            .with_released(released)
+            .unmount()
+            .await?;
+        }
+
+        for (secret_name, service_name) in services {
+            info!(
+                "{} {} is not {} service anymore... cleanup",
+                kind, workload.metadata.name, service_name
+            );
+            let store = self.store.clone().with_private_name(secret_name.clone());
+            self.exclusive(&namespace, &secret_name, async {
+                if self.is_shared(&workload, &service_name).await? {
+                    store.disown(namespace.clone(), service_name, &uid).await
                 } else {
-                    info!("Mount is not set... skipping...")
+                    store.handle_delete(namespace.clone(), service_name).await
                 }
+            })
+            .await?;
+        }
+
+        // Markers are dropped last, so failed cleanup is retried
+        if key_pair_secret.is_some() || self.is_provisioned(&workload) {
+            self.annotate_workload(
+                kind,
+                &workload,
+                json!({
+                    self.config.annotation_key("key-pair-secret"): null,
+                    self.config.annotation_key("provisioned"): null,
+                }),
+            )
+            .await?;
+        }
+
+        self.metrics.write().unwrap().handled_events.inc();
+        Ok(())
+    }
+
+    /// Handle KeyPair events and keep its secrets and status up to date
+    async fn handle_key_pair_event(&self, ev: WatchEvent<KeyPair>) -> Result<()> {
+        match ev {
+            WatchEvent::Added(key_pair) | WatchEvent::Modified(key_pair) => {
+                debug!("KeyPair {:?} applied...", key_pair.metadata.name);
+                self.check_namespace(&key_pair.metadata)?;
+
+                let target = self.key_pair_target(&key_pair);
+                if let Err(e) = self.check_key_pair(&key_pair, &target).await {
+                    let status = keypair::status(&key_pair, None, None, Some(e.to_string()));
+                    keypair::update_status(self.client.clone(), &key_pair, status).await?;
+                    return Err(e);
+                }
+                let res = self
+                    .exclusive(
+                        &target.namespace,
+                        &target.secret_name(),
+                        self.ensure_keys(&target),
+                    )
+                    .await;
+
+                let fingerprint = match target
+                    .store
+                    .public_key(target.namespace.clone(), target.name.clone())
+                    .await?
+                {
+                    Some(public_key) => Some(Generator::fingerprint(&public_key)?),
+                    None => None,
+                };
+                let created_at = target
+                    .store
+                    .created_at(target.namespace.clone(), target.name.clone())
+                    .await?;
+                let status = keypair::status(
+                    &key_pair,
+                    fingerprint,
+                    created_at,
+                    res.as_ref().err().map(|e| e.to_string()),
+                );
+                keypair::update_status(self.client.clone(), &key_pair, status).await?;
+                res?;
 
-                info!("Write to metrics...");
                 self.metrics.write().unwrap().handled_events.inc();
             }
-            WatchEvent::Deleted(deploy) => {
-                info!("Deployment {:?} deleted...", deploy.metadata.name);
+            WatchEvent::Deleted(key_pair) => {
+                info!("KeyPair {:?} deleted...", key_pair.metadata.name);
+                self.check_namespace(&key_pair.metadata)?;
 
-                let service_name = self.get_service_name(deploy.clone())?;
-                self.store
-                    .handle_delete(deploy.metadata.namespace, service_name)
-                    .await?;
+                let target = self.key_pair_target(&key_pair);
+                match self.check_key_pair(&key_pair, &target).await {
+                    Ok(()) => {
+                        self.exclusive(
+                            &target.namespace,
+                            &target.secret_name(),
+                            target
+                                .store
+                                .handle_delete(target.namespace.clone(), target.name.clone()),
+                        )
+                        .await?
+                    }
+                    Err(e) => warn!("Keep secrets of KeyPair {}: {}", key_pair.metadata.name, e),
+                }
 
                 self.metrics.write().unwrap().handled_events.inc();
             }
@@ -176,20 +902,1088 @@ impl Controller {
         Ok(())
     }
 
-    fn get_service_name(&self, deployment: Deployment) -> Result<String> {
-        deployment
+    /// Reconcile owners of modified or deleted operator secret
+    async fn handle_secret_event(&self, ev: WatchEvent<v1Secret>) -> Result<()> {
+        let (secret, deleted) = match ev {
+            WatchEvent::Modified(secret) => (secret, false),
+            WatchEvent::Deleted(secret) => (secret, true),
+            // Secrets are created by operator itself
+            _ => return Ok(()),
+        };
+        // Operator's own writes are reconciled already, reacting to them rescans every service
+        if self.store.is_own_write(&secret) {
+            debug!("Secret {:?} written by operator...", secret.metadata.name);
+            return Ok(());
+        }
+        debug!("Secret {:?} changed...", secret.metadata.name);
+
+        if secret.metadata.name == self.config.secrets.public_name {
+            let namespace = secret
+                .metadata
+                .namespace
+                .clone()
+                .unwrap_or_else(|| "default".into());
+            let data = if deleted {
+                BTreeMap::new()
+            } else {
+                secret.data
+            };
+            self.handle_public_secret(namespace, data).await
+        } else {
+            self.reconcile_owners(&secret.metadata).await
+        }
+    }
+
+    /// Reconcile services whose public keys must be but are missing in public secret of `namespace`
+    async fn handle_public_secret(
+        &self,
+        namespace: String,
+        data: BTreeMap<String, ByteString>,
+    ) -> Result<()> {
+        for private_secret in self.store.managed_secrets().await? {
+            let service_name = match self.store.service_name(&private_secret) {
+                Some(service_name) => service_name,
+                None => continue,
+            };
+            if data.contains_key(&utils::published_name(
+                private_secret.metadata.namespace.as_deref(),
+                &namespace,
+                &utils::public_key_name(&service_name),
+            )) {
+                continue;
+            }
+
+            let metadata = &private_secret.metadata;
+            let store = match metadata
+                .ownerReferences
+                .iter()
+                .find(|o| o.kind == "KeyPair")
+            {
+                Some(owner) => {
+                    let key_pair = keypair::api(self.client.clone())
+                        .within(metadata.namespace.as_deref().unwrap_or("default"))
+                        .get(&owner.name)
+                        .await;
+                    match key_pair {
+                        Ok(key_pair) => self.key_pair_target(&key_pair).store,
+                        Err(e) if utils::is_not_found(&e) => continue,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                None => self.store.clone(),
+            };
+            if store.publishes_to(metadata.namespace.as_deref(), &namespace) {
+                info!(
+                    "Public key of {} is missing in namespace {}",
+                    service_name, namespace
+                );
+                self.reconcile_owners(metadata).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ensure keys and mounts of every existing owner of object
+    async fn reconcile_owners(&self, metadata: &ObjectMeta) -> Result<()> {
+        let namespace = metadata
+            .namespace
+            .clone()
+            .unwrap_or_else(|| "default".into());
+
+        for owner in &metadata.ownerReferences {
+            info!(
+                "Secret {} changed... reconcile {} {}",
+                metadata.name, owner.kind, owner.name
+            );
+            let res = match Kind::from_owner(owner) {
+                Some(kind) => match kind
+                    .api(self.client.clone())
+                    .within(&namespace)
+                    .get(&owner.name)
+                    .await
+                {
+                    // Opted out workloads keep owning secrets until they are cleaned
+                    Ok(workload) if self.is_service(kind, &workload) => {
+                        self.apply(kind, workload, None).await
+                    }
+                    Ok(_) => Ok(()),
+                    Err(e) if utils::is_not_found(&e) => Ok(()),
+                    Err(e) => Err(e.into()),
+                },
+                None if owner.kind == "KeyPair" => match keypair::api(self.client.clone())
+                    .within(&namespace)
+                    .get(&owner.name)
+                    .await
+                {
+                    Ok(key_pair) => {
+                        self.handle_key_pair_event(WatchEvent::Modified(key_pair))
+                            .await
+                    }
+                    Err(e) if utils::is_not_found(&e) => Ok(()),
+                    Err(e) => Err(e.into()),
+                },
+                None => Ok(()),
+            };
+            res?;
+        }
+        Ok(())
+    }
+
+    /// Generate keys if they are missing or broken, otherwise distribute existing ones
+    async fn ensure_keys(&self, target: &Target) -> Result<()> {
+        if target.has_keys().await? {
+            info!("Keys for {} already exist... skipping", target.name);
+            target
+                .store
+                .ensure_encodings(target.namespace.clone(), target.name.clone())
+                .await?;
+            target
+                .store
+                .distribute(target.namespace.clone(), target.name.clone())
+                .await?;
+        } else {
+            let issuer = self.issuer().await?;
+            target
+                .store
+                .handle_add(target.namespace.clone(), target.generate(issuer.as_ref())?)
+                .await?;
+        }
+        self.publish_bundle(target).await?;
+        Ok(())
+    }
+
+    /// Current CA, if service certificates are signed by operator
+    async fn issuer(&self) -> Result<Option<Issuer>> {
+        match &self.ca {
+            Some(ca) => Ok(Some(ca.issuer().await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Publish CA bundle next to target public keys
+    ///
+    /// Returns namespaces where bundle was missing or outdated
+    async fn publish_bundle(&self, target: &Target) -> Result<Vec<String>> {
+        match &self.ca {
+            Some(ca) => {
+                let bundle = ca.bundle().await?;
+                target
+                    .store
+                    .publish_bundle(target.namespace.clone(), &bundle)
+                    .await
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Advance rollover of expiring CA, publish new bundle and reissue service certificates
+    async fn rollover_ca(&self) -> Result<()> {
+        let ca = match &self.ca {
+            Some(ca) => ca,
+            None => return Ok(()),
+        };
+        match ca.rollover().await? {
+            ca::Rollover::Unchanged => Ok(()),
+            ca::Rollover::Bundle => {
+                self.store.republish_bundle(&ca.bundle().await?).await?;
+                ca.published().await
+            }
+            ca::Rollover::Issuer => {
+                self.store.republish_bundle(&ca.bundle().await?).await?;
+                // Certificates signed by replaced CA are reissued as drift
+                self.resync().await
+            }
+        }
+    }
+
+    /// Rotate keys of all managed workloads and key pairs according to rotation policy
+    async fn rotate(&self) -> Result<()> {
+        for target in self.targets().await? {
+            let name = target.name.clone();
+            let (namespace, secret_name) = (target.namespace.clone(), target.secret_name());
+            let res = self
+                .exclusive(&namespace, &secret_name, self.rotate_target(target))
+                .await;
+            if let Err(e) = res {
+                warn!("Cannot rotate keys for {}: {}", name, e);
+            }
+        }
+        self.apply_deferred_triggers().await
+    }
+
+    /// Apply rotation triggers deferred while previous keys were in overlap
+    async fn apply_deferred_triggers(&self) -> Result<()> {
+        // Previous keys of workloads are kept only with rotation configured
+        if self.config.rotation.is_none() {
+            return Ok(());
+        }
+        for (kind, _) in &self.informers {
+            let workloads = kind
+                .api(self.client.clone())
+                .list(&ListParams::default())
+                .await?;
+            for workload in workloads {
+                if !self.is_service(*kind, &workload)
+                    || self.check_namespace(&workload.metadata).is_err()
+                    || self.get_rotation_trigger(&workload).is_none()
+                {
+                    continue;
+                }
+                let name = workload.metadata.name.clone();
+                if let Err(e) = self.handle_apply(*kind, workload).await {
+                    warn!("Cannot apply rotation trigger of {} {}: {}", kind, name, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Renew certificates of all managed workloads and key pairs past `renew_fraction` of lifetime
+    async fn renew(&self) -> Result<()> {
+        let targets = self.targets().await?;
+        // Series of removed services are dropped, others keep last value until renewed
+        let services: BTreeSet<(String, String)> = targets
+            .iter()
+            .map(|target| {
+                let namespace = target.namespace.as_deref().unwrap_or("default");
+                (namespace.to_string(), target.name.clone())
+            })
+            .collect();
+        retain_series(&self.metrics.read().unwrap().certificate_expiry, &services);
+        for target in targets {
+            let name = target.name.clone();
+            let res = self
+                .exclusive(
+                    &target.namespace,
+                    &target.secret_name(),
+                    self.renew_target(&target),
+                )
+                .await;
+            if let Err(e) = res {
+                warn!("Cannot renew certificate for {}: {}", name, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reissue certificate for existing key when it's due and report its expiry
+    async fn renew_target(&self, target: &Target) -> Result<()> {
+        let namespace = target.namespace.clone();
+        let private_key = match target
+            .store
+            .private_key(namespace.clone(), target.name.clone())
+            .await?
+        {
+            Some(private_key) => private_key,
+            None => return Ok(()),
+        };
+        let mut certificate = target
+            .store
+            .certificate(namespace.clone(), target.name.clone())
+            .await?;
+
+        let due = match certificate.as_ref() {
+            Some(certificate) => {
+                let (not_before, not_after) = rsa_generator::validity(certificate)?;
+                let fraction = target.certificate.renew_fraction.clamp(0.0, 1.0);
+                let lifetime = (not_after - not_before).num_seconds() as f64;
+                Utc::now() >= not_before + Duration::seconds((lifetime * fraction) as i64)
+            }
+            // Keys generated before certificates were stored
+            None => true,
+        };
+        if due {
+            info!("Renew certificate of {}", target.name);
+            let issuer = self.issuer().await?;
+            let renewed = target.reissue(&private_key, issuer.as_ref())?;
+            target
+                .store
+                .store_certificate(namespace.clone(), target.name.clone(), &renewed)
+                .await?;
+            self.metrics.read().unwrap().renewed_certificates.inc();
+            certificate = Some(renewed);
+        }
+
+        if let Some(certificate) = certificate {
+            let (_, not_after) = rsa_generator::validity(&certificate)?;
+            self.metrics
+                .read()
+                .unwrap()
+                .certificate_expiry
+                .with_label_values(&[namespace.as_deref().unwrap_or("default"), &target.name])
+                .set(not_after.timestamp());
+        }
+        Ok(())
+    }
+
+    /// Key owners of all managed workloads and key pairs
+    async fn targets(&self) -> Result<Vec<Target>> {
+        let mut targets = vec![];
+        for (kind, _) in &self.informers {
+            let workloads = kind
+                .api(self.client.clone())
+                .list(&ListParams::default())
+                .await?;
+            for workload in workloads {
+                if !self.is_service(*kind, &workload)
+                    || self.get_key_pair_ref(&workload).is_some()
+                    || self.check_namespace(&workload.metadata).is_err()
+                {
+                    continue;
+                }
+                match self.workload_target(*kind, &workload) {
+                    Ok(target) => targets.push(target),
+                    Err(e) => warn!("Skip {} {}: {}", kind, workload.metadata.name, e),
+                }
+            }
+        }
+
+        if self.key_pairs.is_some() {
+            let key_pairs = keypair::api(self.client.clone())
+                .list(&ListParams::default())
+                .await?;
+            for key_pair in key_pairs {
+                if self.check_namespace(&key_pair.metadata).is_err() {
+                    continue;
+                }
+                let target = self.key_pair_target(&key_pair);
+                if let Err(e) = self.check_key_pair(&key_pair, &target).await {
+                    warn!("Skip KeyPair {}: {}", key_pair.metadata.name, e);
+                    continue;
+                }
+                targets.push(target);
+            }
+        }
+        Ok(targets)
+    }
+
+    /// Remove secrets left from workloads deleted while operator was down and public keys of removed secrets
+    async fn sweep(&self) -> Result<()> {
+        let mut services = vec![];
+
+        for secret in self.store.managed_secrets().await? {
+            let service_name = match self.store.service_name(&secret) {
+                Some(service_name) => service_name,
+                None => continue,
+            };
+            if self.has_owners(&secret.metadata).await? {
+                services.push(utils::qualified_name(
+                    secret.metadata.namespace.as_deref(),
+                    &service_name,
+                ));
+                continue;
+            }
+
+            info!("Remove orphaned secret {}", secret.metadata.name);
+            self.exclusive(
+                &secret.metadata.namespace,
+                &secret.metadata.name,
+                self.store
+                    .clone()
+                    .with_private_name(secret.metadata.name.clone())
+                    .handle_delete(secret.metadata.namespace.clone(), service_name),
+            )
+            .await?;
+        }
+
+        self.store.sweep_public(&services).await
+    }
+
+    /// Check that other workloads in the same namespace use `service_name` keys
+    async fn is_shared(&self, workload: &Workload, service_name: &str) -> Result<bool> {
+        let namespace = workload
+            .metadata
+            .namespace
+            .clone()
+            .unwrap_or_else(|| "default".into());
+
+        for (kind, _) in &self.informers {
+            let others = kind
+                .api(self.client.clone())
+                .within(&namespace)
+                .list(&ListParams::default())
+                .await?;
+            for other in others {
+                if other.metadata.uid == workload.metadata.uid
+                    || !self.is_service(*kind, &other)
+                    || self.get_key_pair_ref(&other).is_some()
+                {
+                    continue;
+                }
+                if self.get_service_name(other).ok().as_deref() == Some(service_name) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Check that at least one owner of object still exists
+    ///
+    /// Objects without owners or with unknown owner kinds are treated as owned
+    async fn has_owners(&self, metadata: &ObjectMeta) -> Result<bool> {
+        let namespace = metadata
+            .namespace
+            .clone()
+            .unwrap_or_else(|| "default".into());
+
+        for owner in &metadata.ownerReferences {
+            let uid = match Kind::from_owner(owner) {
+                Some(kind) => kind
+                    .api(self.client.clone())
+                    .within(&namespace)
+                    .get(&owner.name)
+                    .await
+                    .map(|o| o.metadata.uid),
+                None if owner.kind == "KeyPair" => keypair::api(self.client.clone())
+                    .within(&namespace)
+                    .get(&owner.name)
+                    .await
+                    .map(|o| o.metadata.uid),
+                None => return Ok(true),
+            };
+            match uid {
+                Ok(uid) if uid.as_ref() == Some(&owner.uid) => return Ok(true),
+                Ok(_) => {}
+                Err(e) if utils::is_not_found(&e) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(metadata.ownerReferences.is_empty())
+    }
+
+    /// Compare all managed workloads and key pairs with their secrets and mounts, repair drift
+    async fn resync(&self) -> Result<()> {
+        let mut repairs = 0;
+
+        for (kind, _) in &self.informers {
+            let workloads = kind
+                .api(self.client.clone())
+                .list(&ListParams::default())
+                .await?;
+            for workload in workloads {
+                if !self.is_service(*kind, &workload)
+                    || self.check_namespace(&workload.metadata).is_err()
+                {
+                    continue;
+                }
+                let name = workload.metadata.name.clone();
+                match self.resync_workload(*kind, workload).await {
+                    Ok(count) => repairs += count,
+                    Err(e) => warn!("Cannot resync {} {}: {}", kind, name, e),
+                }
+            }
+        }
+
+        if self.key_pairs.is_some() {
+            let key_pairs = keypair::api(self.client.clone())
+                .list(&ListParams::default())
+                .await?;
+            for key_pair in key_pairs {
+                if self.check_namespace(&key_pair.metadata).is_err() {
+                    continue;
+                }
+                let name = key_pair.metadata.name.clone();
+                let target = self.key_pair_target(&key_pair);
+                let res = match self.check_key_pair(&key_pair, &target).await {
+                    Ok(()) => {
+                        self.exclusive(
+                            &target.namespace,
+                            &target.secret_name(),
+                            self.repair_keys(&target),
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match res {
+                    Ok(count) => repairs += count,
+                    Err(e) => warn!("Cannot resync KeyPair {}: {}", name, e),
+                }
+            }
+        }
+
+        info!("Resync finished, {} drifts repaired", repairs);
+        Ok(())
+    }
+
+    /// Repair keys and mounts of workload, returns number of repairs
+    async fn resync_workload(&self, kind: Kind, workload: Workload) -> Result<u64> {
+        let (secret_name, mut repairs) = match self.get_key_pair_ref(&workload) {
+            Some(key_pair) => {
+                let namespace = workload
+                    .metadata
+                    .namespace
+                    .clone()
+                    .unwrap_or_else(|| "default".into());
+                let key_pair = keypair::api(self.client.clone())
+                    .within(&namespace)
+                    .get(&key_pair)
+                    .await?;
+                (self.key_pair_secret(&key_pair)?, 0)
+            }
+            None => {
+                let target = self.workload_target(kind, &workload)?;
+                self.check_conflict(kind, &workload, &target).await?;
+                let trigger = self.get_rotation_trigger(&workload);
+                let repairs = self
+                    .exclusive(&target.namespace, &target.secret_name(), async {
+                        let generates = trigger.is_some() && !target.has_keys().await?;
+                        let repairs = self.repair_keys(&target).await?;
+                        // Keys generated for workload created with trigger satisfy it
+                        if let Some(trigger) = trigger.filter(|_| generates) {
+                            self.mark_rotated(kind, &workload, trigger).await?;
+                        }
+                        Ok::<_, anyhow::Error>(repairs)
+                    })
+                    .await?;
+                // Workloads provisioned before they were marked
+                self.mark_provisioned(kind, &workload).await?;
+                (target.secret_name(), repairs)
+            }
+        };
+
+        if self.mounts_workloads() {
+            let name = workload.metadata.name.clone();
+            let mounter =
+                mounter::Mounter::new(self.client.clone(), kind, workload, self.config.clone())
+                    .await?
+                    .with_secret_name(secret_name);
+            if !mounter.is_mounted()? {
+                warn!("Volumes of {} {} are missing... remount", kind, name);
+                mounter.mount().await?;
+                self.count_repair();
+                repairs += 1;
+            }
+        }
+        Ok(repairs)
+    }
+
+    /// Regenerate missing or broken keys and restore missing public keys
+    ///
+    /// Returns number of repairs
+    async fn repair_keys(&self, target: &Target) -> Result<u64> {
+        let private_key = target
+            .store
+            .private_key(target.namespace.clone(), target.name.clone())
+            .await?
+            .filter(|key| Generator::is_private(target.key_type, key));
+
+        let private_key = match private_key {
+            Some(private_key) => private_key,
+            None => {
+                warn!(
+                    "Keys of {} are missing or broken... regenerate",
+                    target.name
+                );
+                let issuer = self.issuer().await?;
+                target
+                    .store
+                    .handle_add(target.namespace.clone(), target.generate(issuer.as_ref())?)
+                    .await?;
+                self.count_repair();
+                return Ok(1);
+            }
+        };
+
+        let public_key = Generator::public_key_of(&private_key)?;
+        let restored = target
+            .store
+            .restore(target.namespace.clone(), target.name.clone(), &public_key)
+            .await?;
+        for ns in restored.iter() {
+            warn!(
+                "Public key of {} was missing in namespace {}... restored",
+                target.name, ns
+            );
+            self.count_repair();
+        }
+        let mut repairs = restored.len() as u64;
+
+        if target
+            .store
+            .ensure_encodings(target.namespace.clone(), target.name.clone())
+            .await?
+        {
+            warn!(
+                "Key encodings of {} were outdated... rewritten",
+                target.name
+            );
+            self.count_repair();
+            repairs += 1;
+        }
+
+        if let Some(issuer) = self.issuer().await? {
+            let certificate = target
+                .store
+                .certificate(target.namespace.clone(), target.name.clone())
+                .await?;
+            if !certificate.is_some_and(|certificate| issuer.has_signed(&certificate)) {
+                warn!(
+                    "Certificate of {} is not signed by current CA... reissue",
+                    target.name
+                );
+                let certificate = target.reissue(&private_key, Some(&issuer))?;
+                target
+                    .store
+                    .store_certificate(target.namespace.clone(), target.name.clone(), &certificate)
+                    .await?;
+                self.count_repair();
+                repairs += 1;
+            }
+
+            for ns in self.publish_bundle(target).await? {
+                warn!("CA bundle was missing in namespace {}... restored", ns);
+                self.count_repair();
+                repairs += 1;
+            }
+        }
+        Ok(repairs)
+    }
+
+    fn count_repair(&self) {
+        self.metrics.write().unwrap().drift_repairs.inc();
+    }
+
+    /// Retire expired previous key and regenerate outdated one
+    async fn rotate_target(&self, target: Target) -> Result<()> {
+        let rotation = match target.rotation.clone() {
+            Some(rotation) => rotation,
+            None => return Ok(()),
+        };
+        let namespace = target.namespace.clone();
+        let service_name = target.name.clone();
+
+        if !target
+            .store
+            .has_secret(namespace.clone(), service_name.clone())
+            .await?
+        {
+            return Ok(());
+        }
+
+        target
+            .store
+            .retire(namespace.clone(), service_name.clone())
+            .await?;
+
+        let created_at = match target
+            .store
+            .created_at(namespace.clone(), service_name.clone())
+            .await?
+        {
+            Some(created_at) => created_at,
+            None => {
+                // Keys generated before rotation was enabled, start counting from now
+                return target.store.stamp(namespace, service_name).await;
+            }
+        };
+        if Utc::now() - created_at < Duration::seconds(rotation.max_age) {
+            return Ok(());
+        }
+        // Overlap longer than max age, keys are rotated once previous one retires
+        if target
+            .store
+            .overlap_until(namespace, service_name)
+            .await?
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        self.rotate_keys(&target).await
+    }
+
+    /// Regenerate keys keeping previous public key for overlap period
+    async fn rotate_keys(&self, target: &Target) -> Result<()> {
+        info!("Rotate keys for {}", target.name);
+        let overlap = target
+            .rotation
+            .as_ref()
+            .map(|rotation| Duration::seconds(rotation.overlap))
+            .unwrap_or_else(Duration::zero);
+        let issuer = self.issuer().await?;
+        target
+            .store
+            .handle_rotate(
+                target.namespace.clone(),
+                target.generate(issuer.as_ref())?,
+                overlap,
+            )
+            .await?;
+
+        self.metrics.write().unwrap().rotated_keys.inc();
+        Ok(())
+    }
+
+    /// Value of rotate annotation if it was not handled yet
+    fn get_rotation_trigger(&self, workload: &Workload) -> Option<String> {
+        let annotations = &workload.metadata.annotations;
+        let trigger = annotations.get(&self.config.annotation_key("rotate"))?;
+        if annotations.get(&self.config.annotation_key("rotated")) == Some(trigger) {
+            return None;
+        }
+        Some(trigger.clone())
+    }
+
+    /// Record handled rotation trigger and rotation time on workload
+    async fn mark_rotated(&self, kind: Kind, workload: &Workload, trigger: String) -> Result<()> {
+        self.annotate_workload(
+            kind,
+            workload,
+            json!({
+                self.config.annotation_key("rotated"): trigger,
+                self.config.annotation_key("last-rotation"): Utc::now().to_rfc3339(),
+            }),
+        )
+        .await
+    }
+
+    /// Secret of KeyPair mounted to workload by operator
+    fn mounted_key_pair(&self, workload: &Workload) -> Option<String> {
+        workload
             .metadata
             .annotations
+            .get(&self.config.annotation_key("key-pair-secret"))
+            .cloned()
+    }
+
+    /// Check that workload is marked as provisioned with keys
+    fn is_provisioned(&self, workload: &Workload) -> bool {
+        workload
+            .metadata
+            .annotations
+            .contains_key(&self.config.annotation_key("provisioned"))
+    }
+
+    /// Mark workload provisioned with keys, so opt out of unmarked workloads is skipped without API calls
+    async fn mark_provisioned(&self, kind: Kind, workload: &Workload) -> Result<()> {
+        if self.is_provisioned(workload) {
+            return Ok(());
+        }
+        self.annotate_workload(
+            kind,
+            workload,
+            json!({ self.config.annotation_key("provisioned"): "true" }),
+        )
+        .await
+    }
+
+    /// Check that workload without annotation may still have keys or mounts to clean
+    ///
+    /// Workloads provisioned before they were marked are recognized by operator volumes
+    fn was_service(&self, kind: Kind, workload: &Workload) -> bool {
+        self.is_provisioned(workload)
+            || self.mounted_key_pair(workload).is_some()
+            || kind
+                .pod_spec(workload)
+                .map(|pod_spec| {
+                    mounter::has_operator_volumes(pod_spec, &self.config.secrets.public_name)
+                })
+                .unwrap_or(false)
+    }
+
+    /// Record mounted KeyPair secret on workload, so it's unmounted once reference is gone
+    async fn record_key_pair(
+        &self,
+        kind: Kind,
+        workload: &Workload,
+        secret_name: Option<String>,
+    ) -> Result<()> {
+        self.annotate_workload(
+            kind,
+            workload,
+            json!({ self.config.annotation_key("key-pair-secret"): secret_name }),
+        )
+        .await
+    }
+
+    /// Merge `annotations` into workload ones, `null` values remove annotations
+    async fn annotate_workload(
+        &self,
+        kind: Kind,
+        workload: &Workload,
+        annotations: serde_json::Value,
+    ) -> Result<()> {
+        let patch = json!({
+            "metadata": {
+                "annotations": annotations,
+            },
+        });
+
+        kind.api(self.client.clone())
+            .within(
+                &workload
+                    .metadata
+                    .namespace
+                    .clone()
+                    .unwrap_or_else(|| "default".into()),
+            )
+            .patch(
+                &workload.metadata.name,
+                &PatchParams::default(),
+                serde_json::to_vec(&patch)?,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Check object namespace with configured filter
+    fn check_namespace(&self, metadata: &ObjectMeta) -> Result<()> {
+        debug!("Check namespace filtering...");
+
+        let namespace = metadata
+            .namespace
+            .clone()
+            .unwrap_or_else(|| "default".to_string());
+        debug!("Object: {} with namespace: {}", metadata.name, &namespace);
+        if let Some(filter) = self.config.filter.clone() {
+            if !filter.namespaces.is_empty() && !filter.namespaces.contains(&namespace) {
+                info!("Skip this object from different namespace");
+                return Err(anyhow::format_err!(
+                    "Skip this object from different namespace"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn workload_target(&self, kind: Kind, workload: &Workload) -> Result<Target> {
+        Ok(Target {
+            store: self
+                .store
+                .clone()
+                .with_owner(kind.owner_reference(workload))
+                .with_passphrase(self.get_passphrase(workload)?)
+                .with_encodings(self.get_encodings(workload)?),
+            namespace: workload.metadata.namespace.clone(),
+            name: self.get_service_name(workload.clone())?,
+            key_type: self.get_key_type(workload)?,
+            bits: self.config.rsa.bits,
+            rotation: self
+                .config
+                .rotation
+                .as_ref()
+                .map(settings::Rotation::policy),
+            certificate: self.config.certificate.clone(),
+        })
+    }
+
+    /// Secret names KeyPairs can't use, they hold public keys or CA of operator
+    fn reserved_secrets(&self, namespace: Option<&str>) -> Vec<String> {
+        let mut reserved = vec![self.config.secrets.public_name.clone()];
+        if let Some(ca) = &self.config.ca {
+            if namespace.unwrap_or("default") == ca.namespace {
+                reserved.push(ca.secret_name.clone());
+            }
+        }
+        reserved
+    }
+
+    /// Private secret name of KeyPair, checked against operator secrets
+    fn key_pair_secret(&self, key_pair: &KeyPair) -> Result<String> {
+        keypair::check_secret_name(
+            key_pair,
+            &self.reserved_secrets(key_pair.metadata.namespace.as_deref()),
+        )?;
+        Ok(keypair::secret_name(key_pair))
+    }
+
+    /// Check that KeyPair doesn't overwrite secrets or public keys of other owners
+    ///
+    /// Secret of same-named service is checked too, they would share public key entries
+    async fn check_key_pair(&self, key_pair: &KeyPair, target: &Target) -> Result<()> {
+        self.key_pair_secret(key_pair)?;
+        let managed = self.config.annotation_key("managed");
+
+        let secret = target
+            .store
+            .existing_secret(target.namespace.clone(), target.name.clone())
+            .await?;
+        let service_secret = self
+            .store
+            .existing_secret(target.namespace.clone(), target.name.clone())
+            .await?
+            // Secrets not created by operator don't publish keys
+            .filter(|secret| secret.metadata.labels.get(&managed) == Some(&"true".into()));
+        for secret in secret.iter().chain(service_secret.iter()) {
+            if let Some(conflict) = keypair::secret_conflict(key_pair, secret, &managed) {
+                return Err(anyhow::format_err!("{}", conflict));
+            }
+        }
+        Ok(())
+    }
+
+    fn key_pair_target(&self, key_pair: &KeyPair) -> Target {
+        let mut store = self
+            .store
             .clone()
+            .with_private_name(keypair::secret_name(key_pair))
+            .with_owner(keypair::owner_reference(key_pair))
+            .with_passphrase(if self.config.encryption.enabled {
+                Some(Passphrase::Generated)
+            } else {
+                None
+            });
+        if !key_pair.spec.public_namespaces.is_empty() {
+            store = store.with_public_namespaces(key_pair.spec.public_namespaces.clone());
+        }
+
+        Target {
+            store,
+            namespace: key_pair.metadata.namespace.clone(),
+            name: key_pair.metadata.name.clone(),
+            key_type: key_pair.spec.algorithm.unwrap_or(self.config.rsa.key_type),
+            bits: key_pair.spec.size.unwrap_or(self.config.rsa.bits),
+            rotation: key_pair.spec.rotation.clone().or_else(|| {
+                self.config
+                    .rotation
+                    .as_ref()
+                    .map(settings::Rotation::policy)
+            }),
+            certificate: self.config.certificate.clone(),
+        }
+    }
+
+    /// Key algorithm from workload annotation or default from settings
+    fn get_key_type(&self, workload: &Workload) -> Result<KeyType> {
+        match workload
+            .metadata
+            .annotations
+            .get(&self.config.annotation_key("key-type"))
+        {
+            Some(value) => value.parse(),
+            None => Ok(self.config.rsa.key_type),
+        }
+    }
+
+    /// Private key encryption requested by workload annotations or enabled in settings
+    fn get_passphrase(&self, workload: &Workload) -> Result<Option<Passphrase>> {
+        let annotations = &workload.metadata.annotations;
+        if let Some(name) = annotations.get(&self.config.annotation_key("passphrase-secret")) {
+            if !utils::is_valid_name(name) {
+                return Err(anyhow::format_err!(
+                    "Invalid passphrase secret '{}' of workload '{}'",
+                    name,
+                    workload.metadata.name
+                ));
+            }
+            return Ok(Some(Passphrase::Secret(name.clone())));
+        }
+
+        let enabled = match annotations
+            .get(&self.config.annotation_key("encrypt"))
+            .map(String::as_str)
+        {
+            Some("true") => true,
+            Some("false") => false,
+            Some(value) => {
+                return Err(anyhow::format_err!(
+                    "Invalid encrypt annotation '{}' of workload '{}'",
+                    value,
+                    workload.metadata.name
+                ))
+            }
+            None => self.config.encryption.enabled,
+        };
+        Ok(if enabled {
+            Some(Passphrase::Generated)
+        } else {
+            None
+        })
+    }
+
+    /// Private key encodings from workload annotation or config
+    fn get_encodings(&self, workload: &Workload) -> Result<Vec<Encoding>> {
+        match workload
+            .metadata
+            .annotations
+            .get(&self.config.annotation_key("encodings"))
+        {
+            Some(value) => value
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    name.parse().map_err(|_| {
+                        anyhow::format_err!(
+                            "Invalid encoding '{}' of workload '{}'",
+                            name,
+                            workload.metadata.name
+                        )
+                    })
+                })
+                .collect(),
+            None => Ok(self.config.rsa.encodings.clone()),
+        }
+    }
+
+    /// Name of KeyPair referenced by workload
+    fn get_key_pair_ref(&self, workload: &Workload) -> Option<String> {
+        workload
+            .metadata
+            .annotations
+            .get(&self.config.annotation_key("key-pair"))
+            .cloned()
+    }
+
+    /// Jobs spawned by CronJob are served through the CronJob itself
+    fn is_service(&self, kind: Kind, workload: &Workload) -> bool {
+        if workload::is_spawned(kind, workload) {
+            return false;
+        }
+        workload
+            .metadata
+            .annotations
+            .contains_key(&self.config.annotation)
+            || self.get_key_pair_ref(workload).is_some()
+    }
+
+    /// Service name from annotation value, `"true"` means workload name
+    fn get_service_name(&self, workload: Workload) -> Result<String> {
+        let value = workload
+            .metadata
+            .annotations
             .get(&self.config.annotation)
             .ok_or_else(|| {
                 format!(
-                    "deployment '{}' is not evrone service",
-                    deployment.metadata.name.clone()
+                    "workload '{}' is not evrone service",
+                    workload.metadata.name.clone()
                 )
             })
-            .map_err(anyhow::Error::msg)
-            .map(|_| deployment.metadata.name)
+            .map_err(anyhow::Error::msg)?;
+
+        if value == "true" {
+            return Ok(workload.metadata.name);
+        }
+        if !utils::is_valid_name(value) {
+            return Err(anyhow::format_err!(
+                "Invalid service name '{}' of workload '{}'",
+                value,
+                workload.metadata.name
+            ));
+        }
+        Ok(value.clone())
+    }
+}
+
+/// Remove series of `gauge` labelled by namespace and service other than `services`
+fn retain_series(gauge: &IntGaugeVec, services: &BTreeSet<(String, String)>) {
+    for family in gauge.collect() {
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            let label = |name: &str| {
+                labels
+                    .iter()
+                    .find(|label| label.get_name() == name)
+                    .map(|label| label.get_value().to_string())
+                    .unwrap_or_default()
+            };
+            let service = (label("namespace"), label("service"));
+            if !services.contains(&service) {
+                let _ = gauge.remove_label_values(&[&service.0, &service.1]);
+            }
+        }
     }
 }
 
@@ -197,16 +1991,173 @@ impl Controller {
 ///
 /// This returns a `Controller` and calls `poll` on it continuously.
 pub async fn init(cfg: Configuration, settings: settings::Settings) -> Result<Controller> {
-    let c = Controller::new(APIClient::new(cfg), settings).await?; //for app to read
-    let c2 = c.clone(); //for poll thread to write
+    let client = APIClient::new(cfg);
+    let c = Controller::new(client.clone(), settings).await?; //for app to read
+    match c.config.leader_election.clone() {
+        Some(election) => {
+            let identity = env::var("POD_NAME")
+                .or_else(|_| env::var("HOSTNAME"))
+                .unwrap_or_else(|_| format!("key-generator-{}", std::process::id()));
+            info!("Start leader election as <{}>", identity);
+            let elector = leader::Elector::new(client, election, identity);
+            let c1 = c.clone(); //for election thread
+            tokio::spawn(async move { c1.elect(elector).await });
+        }
+        None => c.set_leader(true),
+    }
+    let c5 = c.clone(); //for reconcile on every leadership acquisition
     tokio::spawn(async move {
         loop {
-            if let Err(e) = c2.poll().await {
-                error!("Kube state failed to recover: {}", e);
-                // rely on kube's crash loop backoff to retry sensibly:
+            if c5.is_leader() && !c5.is_synced() {
+                if let Err(e) = c5.take_over().await {
+                    warn!("Cannot reconcile after taking over leadership: {}", e);
+                }
+            }
+            tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+        }
+    });
+    for (kind, informer) in c.informers.clone() {
+        let c2 = c.clone(); //for poll thread to write
+        tokio::spawn(async move {
+            loop {
+                c2.await_sync().await;
+                if let Err(e) = c2.poll(kind, &informer).await {
+                    error!("Kube state of {} failed to recover: {}", kind, e);
+                    // rely on kube's crash loop backoff to retry sensibly:
+                    std::process::exit(1);
+                }
+            }
+        });
+    }
+    if let Some(informer) = c.key_pairs.clone() {
+        let c3 = c.clone(); //for KeyPair poll thread
+        tokio::spawn(async move {
+            loop {
+                c3.await_sync().await;
+                if let Err(e) = c3.poll_key_pairs(&informer).await {
+                    error!("KeyPair state failed to recover: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        });
+    }
+    if let Some(resync) = c.config.resync.clone() {
+        let c7 = c.clone(); //for resync thread
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(std::time::Duration::from_secs(resync.interval)).await;
+                if !c7.is_leader() {
+                    continue;
+                }
+                if let Err(e) = c7.resync().await {
+                    warn!("Cannot resync: {}", e);
+                }
+            }
+        });
+    }
+    let c8 = c.clone(); //for secrets poll thread
+    tokio::spawn(async move {
+        loop {
+            c8.await_sync().await;
+            if let Err(e) = c8.poll_secrets().await {
+                error!("Secret state failed to recover: {}", e);
                 std::process::exit(1);
             }
         }
     });
+    let c10 = c.clone(); //for certificate renewal thread
+    tokio::spawn(async move {
+        loop {
+            if c10.is_leader() {
+                if let Err(e) = c10.renew().await {
+                    warn!("Cannot renew certificates: {}", e);
+                }
+            }
+            tokio::time::delay_for(std::time::Duration::from_secs(
+                c10.config.certificate.renew_interval,
+            ))
+            .await;
+        }
+    });
+    let c6 = c.clone(); //for retry thread
+    tokio::spawn(async move {
+        loop {
+            tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+            if c6.is_leader() {
+                c6.retry().await;
+            }
+        }
+    });
+    if let Some(ca) = c.config.ca.clone() {
+        let c9 = c.clone(); //for CA rollover thread
+        tokio::spawn(async move {
+            loop {
+                if c9.is_leader() {
+                    if let Err(e) = c9.rollover_ca().await {
+                        warn!("Cannot rollover CA: {}", e);
+                    }
+                }
+                tokio::time::delay_for(std::time::Duration::from_secs(ca.interval)).await;
+            }
+        });
+    }
+    if c.config.rotation.is_some() || c.config.key_pairs {
+        let interval = c
+            .config
+            .rotation
+            .as_ref()
+            .map(|rotation| rotation.interval)
+            .unwrap_or(60);
+        let c4 = c.clone(); //for rotation thread
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(std::time::Duration::from_secs(interval)).await;
+                if !c4.is_leader() {
+                    continue;
+                }
+                if let Err(e) = c4.rotate().await {
+                    warn!("Cannot rotate keys: {}", e);
+                }
+            }
+        });
+    }
     Ok(c)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::Opts;
+
+    fn services(gauge: &IntGaugeVec) -> Vec<String> {
+        gauge.collect()[0]
+            .get_metric()
+            .iter()
+            .map(|metric| {
+                let labels = metric.get_label();
+                format!("{}/{}", labels[0].get_value(), labels[1].get_value())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn only_vanished_series_are_removed() {
+        let gauge =
+            IntGaugeVec::new(Opts::new("expiry", "expiry"), &["namespace", "service"]).unwrap();
+        gauge.with_label_values(&["shop", "api"]).set(1);
+        gauge.with_label_values(&["shop", "web"]).set(2);
+        gauge.with_label_values(&["blog", "api"]).set(3);
+
+        let kept = vec![
+            ("shop".to_string(), "api".to_string()),
+            ("blog".to_string(), "api".to_string()),
+            ("blog".to_string(), "new".to_string()),
+        ];
+        retain_series(&gauge, &kept.into_iter().collect());
+
+        let mut remaining = services(&gauge);
+        remaining.sort();
+        assert_eq!(remaining, vec!["blog/api", "shop/api"]);
+        assert_eq!(gauge.with_label_values(&["shop", "api"]).get(), 1);
+    }
+}
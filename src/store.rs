@@ -1,14 +1,43 @@
 use anyhow::Result;
+use chrono::{prelude::*, Duration};
 use kube::{
-    api::{v1Secret, Api, PatchParams, PostParams, Reflector},
+    api::{v1Secret, Api, ListParams, OwnerReference, PatchParams, PostParams, Reflector},
     client::APIClient,
 };
-use std::str::from_utf8;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::from_utf8,
+    sync::{Arc, RwLock},
+};
 
 use k8s_openapi::serde_json;
-use serde_json::json;
+use serde_json::{json, Value};
+
+use crate::{
+    jwks::{self, JWKS_FIELD},
+    rsa_generator::{Encoding, Generator, KeyType},
+    secret::{self, RsaSecret, WriteLog},
+    settings::Settings,
+    utils,
+};
 
-use crate::{rsa_generator::Generator, secret::RsaSecret, settings::Settings, utils};
+/// Private secret field with service certificate
+const CERTIFICATE_FIELD: &str = "cert.pem";
+/// Public secret field with roots of operator CA
+const CA_BUNDLE_FIELD: &str = "ca.crt";
+/// Private secret field with generated passphrase of encrypted private key
+const PASSPHRASE_FIELD: &str = "passphrase";
+/// Seconds JWKS document is served from cache before public secrets are listed again
+const JWKS_MAX_AGE: i64 = 60;
+
+/// Source of passphrase encrypting private key
+#[derive(Clone, Debug, PartialEq)]
+pub enum Passphrase {
+    /// Generated with every key and stored next to it
+    Generated,
+    /// Taken from `passphrase` field of existing secret in service namespace
+    Secret(String),
+}
 
 /// Storage to manage kubernetes secrets
 #[derive(Clone)]
@@ -16,46 +45,583 @@ pub struct Store {
     /// A kube client for performing cluster actions
     client: APIClient,
     config: Settings,
+    /// Private secret name used instead of `<service>-rsa-token`
+    private_name: Option<String>,
+    /// Owner of private secret
+    owner: Option<OwnerReference>,
+    /// Private key is stored unencrypted without passphrase
+    passphrase: Option<Passphrase>,
+    /// Extra private key encodings
+    encodings: Vec<Encoding>,
+    /// Resource versions written by every clone of the store
+    writes: WriteLog,
+    /// JWKS document of all public secrets
+    jwks: Arc<RwLock<jwks::Cache>>,
 }
 
 /// Implements Store methods for manage kubernetes secrets
 impl Store {
     pub async fn new(client: APIClient, config: Settings) -> Result<Self> {
-        Ok(Store { client, config })
+        Ok(Store {
+            client,
+            private_name: None,
+            owner: None,
+            passphrase: None,
+            encodings: config.rsa.encodings.clone(),
+            config,
+            writes: WriteLog::default(),
+            jwks: Arc::new(RwLock::new(jwks::Cache::default())),
+        })
+    }
+
+    /// Use custom private secret name
+    pub fn with_private_name(mut self, name: String) -> Self {
+        self.private_name = Some(name);
+        self
+    }
+
+    /// Make private secret owned by `owner`, so it's removed together with it
+    pub fn with_owner(mut self, owner: Option<OwnerReference>) -> Self {
+        self.owner = owner;
+        self
+    }
+
+    /// Encrypt private key with passphrase
+    pub fn with_passphrase(mut self, passphrase: Option<Passphrase>) -> Self {
+        self.passphrase = passphrase;
+        self
+    }
+
+    /// Write private key in `encodings` instead of configured ones
+    pub fn with_encodings(mut self, encodings: Vec<Encoding>) -> Self {
+        self.encodings = encodings;
+        self
+    }
+
+    /// Use custom namespaces for public key distribution
+    pub fn with_public_namespaces(mut self, namespaces: Vec<String>) -> Self {
+        self.config.secrets.public_namespaces = namespaces;
+        self
+    }
+
+    /// Check that private and public secrets already hold a valid key pair for service
+    pub async fn has_keys(
+        &self,
+        namespace: Option<String>,
+        service_name: String,
+        key_type: KeyType,
+    ) -> Result<bool> {
+        let private_secret = self
+            .private_secret(namespace.clone(), &service_name)
+            .await?;
+        let public_secret = self.public_secret(namespace.clone()).await?;
+
+        let private_key = match private_secret.field("private.pem").await? {
+            Some(key) => key,
+            None => return Ok(false),
+        };
+        // Keys are regenerated when encryption is turned on or off
+        if Generator::is_encrypted(&private_key) != self.passphrase.is_some() {
+            return Ok(false);
+        }
+        let private_key = match self
+            .decrypt(namespace.clone(), &service_name, private_key)
+            .await?
+        {
+            Some(key) => key,
+            None => return Ok(false),
+        };
+        let public_key = match public_secret
+            .field(&utils::public_key_name(&service_name))
+            .await?
+        {
+            Some(key) => key,
+            None => return Ok(false),
+        };
+
+        Ok(Generator::is_pair(key_type, &private_key, &public_key))
+    }
+
+    /// Check that private secret for service exists
+    pub async fn has_secret(
+        &self,
+        namespace: Option<String>,
+        service_name: String,
+    ) -> Result<bool> {
+        let private_secret = self.private_secret(namespace, &service_name).await?;
+        Ok(private_secret.fetch().await?.is_some())
+    }
+
+    /// Copy service public keys from its own namespace to all public namespaces
+    pub async fn distribute(&self, namespace: Option<String>, service_name: String) -> Result<()> {
+        let own_secret = self.public_secret(namespace.clone()).await?;
+
+        let key_name = utils::public_key_name(&service_name);
+        let public_key = own_secret
+            .field(&key_name)
+            .await?
+            .ok_or_else(|| anyhow::format_err!("Missing public key for <{}>", service_name))?;
+        self.publish(namespace.clone(), &key_name, &public_key)
+            .await?;
+
+        let previous_name = utils::previous_key_name(&service_name);
+        if let Some(previous_key) = own_secret.field(&previous_name).await? {
+            self.publish(namespace, &previous_name, &previous_key)
+                .await?;
+        }
+        Ok(())
     }
 
     /// Update existing secret with new rsa fields
     pub async fn handle_add(&self, namespace: Option<String>, generator: Generator) -> Result<()> {
         info!("Add token fields for <{}>", &generator.name);
 
-        let mut private_secret = RsaSecret::new(
-            self.client.clone(),
-            utils::secret_name(generator.name.clone()),
+        let mut private_secret = self
+            .private_secret(namespace.clone(), &generator.name)
+            .await?;
+
+        let passphrase = match self.passphrase.as_ref() {
+            Some(Passphrase::Generated) => {
+                let passphrase = Generator::passphrase(self.config.encryption.passphrase_length)?;
+                private_secret
+                    .add_field(PASSPHRASE_FIELD, &passphrase)
+                    .await?;
+                Some(passphrase.into_bytes())
+            }
+            Some(Passphrase::Secret(name)) => {
+                Some(self.secret_passphrase(namespace.clone(), name).await?)
+            }
+            None => None,
+        };
+        let private_key = match passphrase.as_ref() {
+            Some(passphrase) => Generator::encrypt(&generator.private_key, passphrase)?,
+            None => generator.private_key.clone(),
+        };
+
+        let encoded = Generator::encode(
+            &generator.private_key,
+            &generator.name,
+            namespace.as_deref(),
+            &self.encodings,
+            passphrase.as_deref(),
+        )?;
+        for (name, value) in encoded.iter() {
+            private_secret.add_field(name, value).await?;
+        }
+
+        private_secret
+            .add_field("private.pem", from_utf8(&private_key)?)
+            .await?
+            .add_field(CERTIFICATE_FIELD, from_utf8(&generator.certificate)?)
+            .await?
+            .annotate(
+                &self.config.annotation_key("created-at"),
+                &Utc::now().to_rfc3339(),
+            )
+            .await?
+            .update()
+            .await?;
+        self.clean_stale(&private_secret, &encoded).await?;
+
+        self.publish(
             namespace.clone(),
+            &utils::public_key_name(&generator.name),
+            &generator.public_key,
         )
         .await?;
+        self.publish(
+            namespace.clone(),
+            &utils::certificate_name(&generator.name),
+            &generator.certificate,
+        )
+        .await?;
+        Ok(())
+    }
 
-        private_secret
-            .add_field("private.pem", from_utf8(&generator.private_key)?)
+    /// Replace service keys with new ones
+    ///
+    /// Previous public key stays published next to the new one until `overlap` passed,
+    /// keys are not rotated again before it's retired, it would be replaced unannounced
+    pub async fn handle_rotate(
+        &self,
+        namespace: Option<String>,
+        generator: Generator,
+        overlap: Duration,
+    ) -> Result<()> {
+        if let Some(expires_at) = self
+            .overlap_until(namespace.clone(), generator.name.clone())
             .await?
-            .update()
+        {
+            return Err(anyhow::format_err!(
+                "Previous key of <{}> is published until {}, rotate after it's retired",
+                generator.name,
+                expires_at.to_rfc3339()
+            ));
+        }
+        info!("Rotate token fields for <{}>", &generator.name);
+
+        let current_key = self
+            .public_secret(namespace.clone())
+            .await?
+            .field(&utils::public_key_name(&generator.name))
             .await?;
 
-        let key_name = format!("{}.pem", generator.name);
+        // Without overlap previous key is dropped right away
+        let current_key = current_key.filter(|_| overlap > Duration::zero());
+        if let Some(current_key) = current_key {
+            self.publish(
+                namespace.clone(),
+                &utils::previous_key_name(&generator.name),
+                &current_key,
+            )
+            .await?;
 
-        // For current namespace update public secret
-        info!("For current namespace update public secret...");
+            self.private_secret(namespace.clone(), &generator.name)
+                .await?
+                .annotate(
+                    &self.config.annotation_key("previous-expires-at"),
+                    &(Utc::now() + overlap).to_rfc3339(),
+                )
+                .await?
+                .update()
+                .await?;
+        }
 
-        let mut public_secret = RsaSecret::new(
-            self.client.clone(),
-            self.config.secrets.public_name.clone(),
-            namespace,
+        self.handle_add(namespace, generator).await
+    }
+
+    /// End of overlap period of previous public key of service, `None` if it's over or there is none
+    pub async fn overlap_until(
+        &self,
+        namespace: Option<String>,
+        service_name: String,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let expires_at = self
+            .private_secret(namespace, &service_name)
+            .await?
+            .annotation(&self.config.annotation_key("previous-expires-at"))
+            .await?
+            .and_then(|value| DateTime::parse_from_rfc3339(&value).ok())
+            .map(|value| value.with_timezone(&Utc));
+        Ok(expires_at.filter(|expires_at| *expires_at > Utc::now()))
+    }
+
+    /// Remove previous public key of service when its overlap period is over
+    pub async fn retire(&self, namespace: Option<String>, service_name: String) -> Result<()> {
+        let private_secret = self
+            .private_secret(namespace.clone(), &service_name)
+            .await?;
+        let annotation = self.config.annotation_key("previous-expires-at");
+
+        let expires_at = private_secret
+            .annotation(&annotation)
+            .await?
+            .and_then(|value| DateTime::parse_from_rfc3339(&value).ok());
+        match expires_at {
+            Some(expires_at) if expires_at <= Utc::now() => {}
+            _ => return Ok(()),
+        }
+
+        info!("Retire previous public key for <{}>", service_name);
+        let previous_name = utils::previous_key_name(&service_name);
+        self.unpublish(namespace, vec![previous_name]).await?;
+        private_secret.remove_annotation(&annotation).await?;
+        Ok(())
+    }
+
+    /// Current private key of service, decrypted if passphrase is available
+    pub async fn private_key(
+        &self,
+        namespace: Option<String>,
+        service_name: String,
+    ) -> Result<Option<Vec<u8>>> {
+        let private_key = self
+            .private_secret(namespace.clone(), &service_name)
+            .await?
+            .field("private.pem")
+            .await?;
+        Ok(match private_key {
+            // Key which can't be decrypted with generated passphrase is returned as is and treated as broken
+            Some(key) if Generator::is_encrypted(&key) => Some(
+                self.decrypt(namespace, &service_name, key.clone())
+                    .await?
+                    .unwrap_or(key),
+            ),
+            key => key,
+        })
+    }
+
+    /// Write missing fields of configured encodings for existing key and remove unused ones
+    ///
+    /// Returns `true` when private secret was changed
+    pub async fn ensure_encodings(
+        &self,
+        namespace: Option<String>,
+        service_name: String,
+    ) -> Result<bool> {
+        // Broken keys are regenerated together with all encodings
+        let private_key = match self
+            .private_key(namespace.clone(), service_name.clone())
+            .await?
+        {
+            Some(key) if !Generator::is_encrypted(&key) => key,
+            _ => return Ok(false),
+        };
+        let mut private_secret = self
+            .private_secret(namespace.clone(), &service_name)
+            .await?;
+        let data = private_secret.data().await?.unwrap_or_default();
+        let passphrase = match self.passphrase.as_ref() {
+            Some(Passphrase::Generated) => data.get(PASSPHRASE_FIELD).cloned(),
+            Some(Passphrase::Secret(name)) => {
+                Some(self.secret_passphrase(namespace.clone(), name).await?)
+            }
+            None => None,
+        };
+
+        let encoded = Generator::encode(
+            &private_key,
+            &service_name,
+            namespace.as_deref(),
+            &self.encodings,
+            passphrase.as_deref(),
+        )?;
+        let missing: Vec<(&String, &Vec<u8>)> = encoded
+            .iter()
+            .filter(|(name, encoded)| match data.get(*name) {
+                // Unencrypted DER written before it was PKCS#8 is rewritten
+                Some(value) if *name == "private.der" => {
+                    passphrase.is_none() && !Generator::is_pkcs8_der(value)
+                }
+                // JWK is deterministic, so one written with an outdated `kid` differs
+                Some(value) if *name == "private.jwk.json" => *value != **encoded,
+                Some(_) => false,
+                None => true,
+            })
+            .collect();
+        let changed = !missing.is_empty();
+        if changed {
+            info!("Write missing key encodings for <{}>", service_name);
+            for (name, value) in missing {
+                private_secret.add_field(name, value).await?;
+            }
+            private_secret.update().await?;
+        }
+        Ok(self.clean_stale(&private_secret, &encoded).await? || changed)
+    }
+
+    /// Remove fields of encodings which are not written anymore and unused generated passphrase
+    ///
+    /// Returns `true` when some fields were removed
+    async fn clean_stale(
+        &self,
+        private_secret: &RsaSecret,
+        encoded: &BTreeMap<String, Vec<u8>>,
+    ) -> Result<bool> {
+        let data = private_secret.data().await?.unwrap_or_default();
+        let mut stale: Vec<String> = Encoding::all_fields()
+            .into_iter()
+            .filter(|name| data.contains_key(*name) && !encoded.contains_key(*name))
+            .map(String::from)
+            .collect();
+        // Passphrase generated for previous key is useless now
+        if self.passphrase != Some(Passphrase::Generated) && data.contains_key(PASSPHRASE_FIELD) {
+            stale.push(PASSPHRASE_FIELD.into());
+        }
+        if stale.is_empty() {
+            return Ok(false);
+        }
+        private_secret.clean(stale).await?;
+        Ok(true)
+    }
+
+    /// Decrypt encrypted private key with its passphrase, plain key is returned as is
+    ///
+    /// Returns `None` when generated passphrase is missing or doesn't match the key, so keys are
+    /// regenerated. Key not matching passphrase from user secret is kept and reported as error.
+    async fn decrypt(
+        &self,
+        namespace: Option<String>,
+        service_name: &str,
+        private_key: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        if !Generator::is_encrypted(&private_key) {
+            return Ok(Some(private_key));
+        }
+        match self.passphrase.as_ref() {
+            Some(Passphrase::Secret(name)) => {
+                let passphrase = self.secret_passphrase(namespace, name).await?;
+                Generator::decrypt(&private_key, &passphrase)
+                    .map(Some)
+                    .map_err(|_| {
+                        anyhow::format_err!(
+                            "Private key of <{}> can't be decrypted with passphrase from secret {}",
+                            service_name,
+                            name
+                        )
+                    })
+            }
+            // Generated passphrase is kept even when encryption is turned off
+            _ => {
+                let passphrase = self
+                    .private_secret(namespace, service_name)
+                    .await?
+                    .field(PASSPHRASE_FIELD)
+                    .await?;
+                Ok(passphrase
+                    .and_then(|passphrase| Generator::decrypt(&private_key, &passphrase).ok()))
+            }
+        }
+    }
+
+    /// Passphrase from existing secret `name`
+    async fn secret_passphrase(&self, namespace: Option<String>, name: &str) -> Result<Vec<u8>> {
+        RsaSecret::new(self.client.clone(), name.to_string(), namespace)
+            .await?
+            .field(PASSPHRASE_FIELD)
+            .await?
+            .ok_or_else(|| anyhow::format_err!("Missing passphrase in secret {}", name))
+    }
+
+    /// Publish service public key and certificate where they are missing or differ
+    ///
+    /// Returns namespaces where public key or certificate was restored
+    pub async fn restore(
+        &self,
+        namespace: Option<String>,
+        service_name: String,
+        public_key: &[u8],
+    ) -> Result<Vec<String>> {
+        let mut restored = self
+            .publish(
+                namespace.clone(),
+                &utils::public_key_name(&service_name),
+                public_key,
+            )
+            .await?;
+
+        // Keys generated before certificates were stored have none
+        if let Some(certificate) = self
+            .certificate(namespace.clone(), service_name.clone())
+            .await?
+        {
+            let published = self
+                .publish(
+                    namespace.clone(),
+                    &utils::certificate_name(&service_name),
+                    &certificate,
+                )
+                .await?;
+            for ns in published {
+                if !restored.contains(&ns) {
+                    restored.push(ns);
+                }
+            }
+        }
+        Ok(restored)
+    }
+
+    /// Current certificate of service
+    pub async fn certificate(
+        &self,
+        namespace: Option<String>,
+        service_name: String,
+    ) -> Result<Option<Vec<u8>>> {
+        self.private_secret(namespace, &service_name)
+            .await?
+            .field(CERTIFICATE_FIELD)
+            .await
+    }
+
+    /// Replace certificate of service keeping its keys
+    pub async fn store_certificate(
+        &self,
+        namespace: Option<String>,
+        service_name: String,
+        certificate: &[u8],
+    ) -> Result<()> {
+        self.private_secret(namespace.clone(), &service_name)
+            .await?
+            .add_field(CERTIFICATE_FIELD, from_utf8(certificate)?)
+            .await?
+            .update()
+            .await?;
+        self.publish(
+            namespace.clone(),
+            &utils::certificate_name(&service_name),
+            certificate,
         )
         .await?;
+        Ok(())
+    }
 
-        info!("Add field to public secret...");
-        public_secret
-            .add_field(&key_name, from_utf8(&generator.public_key)?)
+    /// Publish CA bundle to public namespaces of service
+    ///
+    /// Returns namespaces where bundle was updated
+    pub async fn publish_bundle(
+        &self,
+        namespace: Option<String>,
+        bundle: &[u8],
+    ) -> Result<Vec<String>> {
+        self.publish_as(namespace, bundle, |_| CA_BUNDLE_FIELD.into())
+            .await
+    }
+
+    /// Update CA bundle in public secrets of all namespaces, e.g. after CA rollover
+    pub async fn republish_bundle(&self, bundle: &[u8]) -> Result<()> {
+        let params = ListParams {
+            field_selector: Some(format!("metadata.name={}", self.config.secrets.public_name)),
+            ..ListParams::default()
+        };
+        for secret in Api::v1Secret(self.client.clone()).list(&params).await? {
+            let namespace = secret
+                .metadata
+                .namespace
+                .unwrap_or_else(|| "default".into());
+            if let Err(e) = self
+                .publish_field(namespace.clone(), CA_BUNDLE_FIELD, bundle)
+                .await
+            {
+                warn!("Cannot update CA bundle in namespace {}: {}", namespace, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Current public key of service
+    pub async fn public_key(
+        &self,
+        namespace: Option<String>,
+        service_name: String,
+    ) -> Result<Option<Vec<u8>>> {
+        let key_name = utils::public_key_name(&service_name);
+        self.public_secret(namespace).await?.field(&key_name).await
+    }
+
+    /// Time when current service keys were generated
+    pub async fn created_at(
+        &self,
+        namespace: Option<String>,
+        service_name: String,
+    ) -> Result<Option<DateTime<Utc>>> {
+        let created_at = self
+            .private_secret(namespace, &service_name)
+            .await?
+            .annotation(&self.config.annotation_key("created-at"))
+            .await?
+            .and_then(|value| DateTime::parse_from_rfc3339(&value).ok())
+            .map(|value| value.with_timezone(&Utc));
+        Ok(created_at)
+    }
+
+    /// Mark existing service keys as generated now
+    pub async fn stamp(&self, namespace: Option<String>, service_name: String) -> Result<()> {
+        self.private_secret(namespace, &service_name)
+            .await?
+            .annotate(
+                &self.config.annotation_key("created-at"),
+                &Utc::now().to_rfc3339(),
+            )
             .await?
             .update()
             .await?;
@@ -70,26 +636,514 @@ impl Store {
     ) -> Result<()> {
         info!("Delete token fields for <{}>", service_name);
 
-        let key_name = format!("{}.pem", service_name);
-
-        let public_secret = RsaSecret::new(
-            self.client.clone(),
-            self.config.secrets.public_name.clone(),
+        self.unpublish(
             namespace.clone(),
+            vec![
+                utils::public_key_name(&service_name),
+                utils::previous_key_name(&service_name),
+                utils::certificate_name(&service_name),
+            ],
         )
         .await?;
 
-        public_secret.clean(vec![key_name.clone()]).await?;
+        let private_secret = self.private_secret(namespace, &service_name).await?;
+        private_secret
+            .clean(
+                vec!["private.pem", CERTIFICATE_FIELD, PASSPHRASE_FIELD]
+                    .into_iter()
+                    .chain(Encoding::all_fields())
+                    .map(String::from)
+                    .collect(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Write public secret field of service to all public namespaces
+    ///
+    /// Returns namespaces where field was updated
+    async fn publish(
+        &self,
+        namespace: Option<String>,
+        key_name: &str,
+        value: &[u8],
+    ) -> Result<Vec<String>> {
+        let own_namespace = namespace.clone().unwrap_or_else(|| "default".into());
+        self.publish_as(namespace, value, |ns| {
+            utils::published_name(Some(&own_namespace), ns, key_name)
+        })
+        .await
+    }
+
+    /// Write public secret field named by `key_name` in every public namespace
+    async fn publish_as(
+        &self,
+        namespace: Option<String>,
+        value: &[u8],
+        key_name: impl Fn(&str) -> String,
+    ) -> Result<Vec<String>> {
+        let own_namespace = namespace.clone().unwrap_or_else(|| "default".into());
+        let mut updated = vec![];
+
+        for ns in self.public_namespaces(namespace).await? {
+            match self.publish_field(ns.clone(), &key_name(&ns), value).await {
+                Ok(true) => updated.push(ns),
+                Ok(false) => {}
+                // Own namespace public secret is mounted to service, so it's required
+                Err(e) if ns == own_namespace => return Err(e),
+                Err(e) => warn!("Cannot update public secret in namespace {}: {}", ns, e),
+            }
+        }
+        Ok(updated)
+    }
+
+    /// Write public secret field in namespace `ns`, returns `false` if it's up to date
+    ///
+    /// Written fields are recorded in `entries` annotation, only they are removed by sweep
+    async fn publish_field(&self, ns: String, key_name: &str, value: &[u8]) -> Result<bool> {
+        let mut public_secret = self.public_secret(Some(ns.clone())).await?;
+        let annotation = self.config.annotation_key("entries");
+
+        let existing = public_secret.fetch().await?;
+        let changed = existing
+            .as_ref()
+            .and_then(|secret| secret.data.get(key_name))
+            .map(|current| current.0.as_slice())
+            != Some(value);
+        let mut entries = existing
+            .map(|secret| parse_entries(secret.metadata.annotations.get(&annotation)))
+            .unwrap_or_default();
+        if !changed && entries.contains(key_name) {
+            return Ok(false);
+        }
+
+        if changed {
+            info!("Update <{}> in public secret of namespace {}", key_name, ns);
+            public_secret.add_field(key_name, from_utf8(value)?).await?;
+        }
+        entries.insert(key_name.to_string());
+        public_secret
+            .annotate(&annotation, &format_entries(&entries))
+            .await?
+            .update()
+            .await?;
+        if changed {
+            self.update_jwks(ns).await?;
+        }
+        Ok(changed)
+    }
+
+    /// Remove public secret fields of service from all public namespaces
+    async fn unpublish(&self, namespace: Option<String>, key_names: Vec<String>) -> Result<()> {
+        let own_namespace = namespace.clone().unwrap_or_else(|| "default".into());
+        for ns in self.public_namespaces(namespace).await? {
+            let published: Vec<String> = key_names
+                .iter()
+                .map(|key_name| utils::published_name(Some(&own_namespace), &ns, key_name))
+                .collect();
+            self.unpublish_from(ns, &published).await;
+        }
+        Ok(())
+    }
+
+    /// Remove public secret fields in namespace `ns`
+    async fn unpublish_from(&self, ns: String, key_names: &[String]) {
+        if let Err(e) = self.try_unpublish_from(ns.clone(), key_names).await {
+            warn!("Cannot clean public secret in namespace {}: {}", ns, e);
+        }
+    }
+
+    async fn try_unpublish_from(&self, ns: String, key_names: &[String]) -> Result<()> {
+        let mut public_secret = self.public_secret(Some(ns.clone())).await?;
+        let (data, annotations) = public_secret
+            .fetch()
+            .await?
+            .map(|secret| (secret.data, secret.metadata.annotations))
+            .unwrap_or_default();
+        let mut present: Vec<String> = key_names
+            .iter()
+            .filter(|key_name| data.contains_key(*key_name))
+            .cloned()
+            .collect();
+        if present.is_empty() {
+            return Ok(());
+        }
+
+        // Drop JWKS and CA bundle with the last key, so empty secret is removed
+        let has_other_keys = data
+            .keys()
+            .any(|name| name != JWKS_FIELD && name != CA_BUNDLE_FIELD && !present.contains(name));
+        if !has_other_keys {
+            present.push(JWKS_FIELD.into());
+            if data.contains_key(CA_BUNDLE_FIELD) {
+                present.push(CA_BUNDLE_FIELD.into());
+            }
+        }
+
+        public_secret.clean(present.clone()).await?;
+        if has_other_keys {
+            let annotation = self.config.annotation_key("entries");
+            let mut entries = parse_entries(annotations.get(&annotation));
+            entries.retain(|entry| !present.contains(entry));
+            public_secret
+                .annotate(&annotation, &format_entries(&entries))
+                .await?
+                .update()
+                .await?;
+            self.update_jwks(ns).await?;
+        } else {
+            self.jwks.write().unwrap().refresh(&ns, None);
+        }
+        Ok(())
+    }
+
+    /// Rebuild JWKS field of public secret from its public keys
+    ///
+    /// Cached JWKS document is refreshed with it
+    async fn update_jwks(&self, namespace: String) -> Result<()> {
+        let mut public_secret = self.public_secret(Some(namespace.clone())).await?;
+        let data = match public_secret.data().await? {
+            Some(data) => data,
+            None => {
+                self.jwks.write().unwrap().refresh(&namespace, None);
+                return Ok(());
+            }
+        };
+
+        let set = jwks::jwks(&namespace, &data);
+        let document = serde_json::to_string(&set)?;
+        self.jwks.write().unwrap().refresh(&namespace, Some(set));
+        if data.get(JWKS_FIELD).map(Vec::as_slice) == Some(document.as_bytes()) {
+            return Ok(());
+        }
+
+        public_secret
+            .add_field(JWKS_FIELD, &document)
+            .await?
+            .update()
+            .await?;
+        Ok(())
+    }
+
+    /// Private secrets created by operator in all namespaces
+    pub async fn managed_secrets(&self) -> Result<Vec<v1Secret>> {
+        let params = ListParams {
+            label_selector: Some(format!("{}=true", self.config.annotation_key("managed"))),
+            ..ListParams::default()
+        };
+        Ok(Api::v1Secret(self.client.clone())
+            .list(&params)
+            .await?
+            .items)
+    }
+
+    /// Private secrets owned by object with `uid` in namespace
+    pub async fn owned_secrets(
+        &self,
+        namespace: Option<String>,
+        uid: &str,
+    ) -> Result<Vec<v1Secret>> {
+        let params = ListParams {
+            label_selector: Some(format!("{}=true", self.config.annotation_key("managed"))),
+            ..ListParams::default()
+        };
+        let secrets = Api::v1Secret(self.client.clone())
+            .within(&namespace.unwrap_or_else(|| "default".into()))
+            .list(&params)
+            .await?
+            .into_iter()
+            .filter(|secret| {
+                secret
+                    .metadata
+                    .ownerReferences
+                    .iter()
+                    .any(|owner| owner.uid == uid)
+            })
+            .collect();
+        Ok(secrets)
+    }
+
+    /// Objects owning service private secret
+    pub async fn owners(
+        &self,
+        namespace: Option<String>,
+        service_name: String,
+    ) -> Result<Vec<OwnerReference>> {
+        Ok(self
+            .existing_secret(namespace, service_name)
+            .await?
+            .map(|secret| secret.metadata.ownerReferences)
+            .unwrap_or_default())
+    }
+
+    /// Service private secret, `None` if it's missing
+    pub async fn existing_secret(
+        &self,
+        namespace: Option<String>,
+        service_name: String,
+    ) -> Result<Option<v1Secret>> {
+        self.private_secret(namespace, &service_name)
+            .await?
+            .fetch()
+            .await
+    }
+
+    /// Remove object with `uid` from owners of service private secret
+    pub async fn disown(
+        &self,
+        namespace: Option<String>,
+        service_name: String,
+        uid: &str,
+    ) -> Result<()> {
+        self.private_secret(namespace, &service_name)
+            .await?
+            .disown(uid)
+            .await?;
+        Ok(())
+    }
+
+    /// Service name of private secret created by operator
+    pub fn service_name(&self, secret: &v1Secret) -> Option<String> {
+        secret
+            .metadata
+            .annotations
+            .get(&self.config.annotation_key("service-name"))
+            .cloned()
+    }
+
+    /// Remove public keys of services whose private secret is gone from public secrets in all namespaces
+    ///
+    /// `services` are qualified names of services with managed private secrets, others are kept
+    /// while their unlabelled private secret exists. Only entries recorded as written by operator
+    /// are removed, entries added by hand or before they were recorded are kept.
+    pub async fn sweep_public(&self, services: &[String]) -> Result<()> {
+        let annotation = self.config.annotation_key("entries");
+        let params = ListParams {
+            field_selector: Some(format!("metadata.name={}", self.config.secrets.public_name)),
+            ..ListParams::default()
+        };
+        // Same entries are published to several namespaces
+        let mut existing: BTreeMap<String, bool> = services
+            .iter()
+            .map(|service| (service.clone(), true))
+            .collect();
+
+        for secret in Api::v1Secret(self.client.clone()).list(&params).await? {
+            let namespace = secret
+                .metadata
+                .namespace
+                .unwrap_or_else(|| "default".into());
+            let recorded = parse_entries(secret.metadata.annotations.get(&annotation));
+            let mut orphans = vec![];
+            for name in secret.data.keys() {
+                if !recorded.contains(name) || name == CA_BUNDLE_FIELD {
+                    continue;
+                }
+                let (service_namespace, service_name) =
+                    match utils::parse_entry_name(&namespace, name) {
+                        Some(service) => service,
+                        None => {
+                            debug!(
+                                "Skip public entry <{}> of unknown format in namespace {}",
+                                name, namespace
+                            );
+                            continue;
+                        }
+                    };
+                let service = utils::qualified_name(Some(service_namespace), service_name);
+                let exists = match existing.get(&service) {
+                    Some(exists) => *exists,
+                    None => {
+                        // Secrets created before they were labelled
+                        let exists = self
+                            .has_secret(Some(service_namespace.into()), service_name.into())
+                            .await?;
+                        existing.insert(service, exists);
+                        exists
+                    }
+                };
+                if !exists {
+                    orphans.push(name.clone());
+                }
+            }
+            if orphans.is_empty() {
+                continue;
+            }
+
+            info!(
+                "Remove orphaned public keys {:?} in namespace {}",
+                orphans, namespace
+            );
+            self.unpublish_from(namespace, &orphans).await;
+        }
+        Ok(())
+    }
+
+    /// JWKS document with public keys of all managed services in cluster
+    ///
+    /// Public secrets are listed only when cached document is older than `JWKS_MAX_AGE`,
+    /// operator's own writes keep it up to date meanwhile
+    pub async fn jwks(&self) -> Result<Value> {
+        let now = Utc::now();
+        let max_age = Duration::seconds(JWKS_MAX_AGE);
+        if let Some(document) = self.jwks.read().unwrap().document(now, max_age) {
+            return Ok(document);
+        }
 
-        let private_secret = RsaSecret::new(
+        let params = ListParams {
+            field_selector: Some(format!("metadata.name={}", self.config.secrets.public_name)),
+            ..ListParams::default()
+        };
+        let sets = Api::v1Secret(self.client.clone())
+            .list(&params)
+            .await?
+            .into_iter()
+            .map(|secret| {
+                let data = secret
+                    .data
+                    .into_iter()
+                    .map(|(name, value)| (name, value.0))
+                    .collect();
+                let namespace = secret
+                    .metadata
+                    .namespace
+                    .unwrap_or_else(|| "default".into());
+                let set = jwks::jwks(&namespace, &data);
+                (namespace, set)
+            })
+            .collect::<BTreeMap<_, _>>();
+        let document = jwks::merge(sets.values().cloned().collect());
+        self.jwks.write().unwrap().load(sets, now);
+        Ok(document)
+    }
+
+    /// Check that `secret` event is caused by a write of the store itself
+    pub fn is_own_write(&self, secret: &v1Secret) -> bool {
+        secret::take_write(&self.writes, secret.metadata.resourceVersion.as_deref())
+    }
+
+    /// Forget own writes, e.g. when their events are skipped by a watch restart
+    pub fn forget_writes(&self) {
+        self.writes.write().unwrap().clear();
+    }
+
+    /// Name of private secret with service keys
+    pub fn secret_name(&self, service_name: &str) -> String {
+        self.private_name
+            .clone()
+            .unwrap_or_else(|| utils::secret_name(service_name.to_string()))
+    }
+
+    async fn private_secret(
+        &self,
+        namespace: Option<String>,
+        service_name: &str,
+    ) -> Result<RsaSecret> {
+        let mut secret = RsaSecret::new(
             self.client.clone(),
-            utils::secret_name(service_name.clone()),
+            self.secret_name(service_name),
             namespace,
         )
-        .await?;
+        .await?
+        .label(&self.config.annotation_key("managed"), "true")
+        .log_writes(self.writes.clone());
+        if let Some(owner) = self.owner.clone() {
+            secret = secret.own(owner);
+        }
+        secret
+            .annotate(&self.config.annotation_key("service-name"), service_name)
+            .await?;
+        Ok(secret)
+    }
 
-        private_secret.clean(vec!["private.pem".into()]).await?;
+    async fn public_secret(&self, namespace: Option<String>) -> Result<RsaSecret> {
+        Ok(RsaSecret::new(
+            self.client.clone(),
+            self.config.secrets.public_name.clone(),
+            namespace,
+        )
+        .await?
+        .label(&self.config.annotation_key("managed"), "true")
+        .log_writes(self.writes.clone()))
+    }
 
-        Ok(())
+    /// Check that public keys of service from `namespace` are published to `target`
+    ///
+    /// `target` is an existing namespace, so wildcard matches it without listing namespaces
+    pub fn publishes_to(&self, namespace: Option<&str>, target: &str) -> bool {
+        namespace.unwrap_or("default") == target
+            || self
+                .config
+                .secrets
+                .public_namespaces
+                .iter()
+                .any(|ns| ns == "*" || ns == target)
+    }
+
+    /// Namespaces for public keys distribution
+    ///
+    /// Always starts with service own namespace,
+    /// wildcard `*` in `secrets.public_namespaces` means all cluster namespaces
+    async fn public_namespaces(&self, namespace: Option<String>) -> Result<Vec<String>> {
+        let mut namespaces = vec![namespace.unwrap_or_else(|| "default".into())];
+
+        let configured = if self
+            .config
+            .secrets
+            .public_namespaces
+            .iter()
+            .any(|ns| ns == "*")
+        {
+            Api::v1Namespace(self.client.clone())
+                .list(&ListParams::default())
+                .await?
+                .into_iter()
+                .map(|ns| ns.metadata.name)
+                .collect()
+        } else {
+            self.config.secrets.public_namespaces.clone()
+        };
+
+        for ns in configured {
+            if !namespaces.contains(&ns) {
+                namespaces.push(ns);
+            }
+        }
+        Ok(namespaces)
+    }
+}
+
+/// Entry names of comma separated `entries` annotation value
+fn parse_entries(value: Option<&String>) -> BTreeSet<String> {
+    value
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn format_entries(entries: &BTreeSet<String>) -> String {
+    entries.iter().cloned().collect::<Vec<_>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_round_trip() {
+        assert!(parse_entries(None).is_empty());
+
+        let value = " api.pem,,shop__api.crt ".to_string();
+        let entries = parse_entries(Some(&value));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(format_entries(&entries), "api.pem,shop__api.crt");
+        assert_eq!(parse_entries(Some(&format_entries(&entries))), entries);
     }
 }
@@ -1,3 +1,210 @@
 pub fn secret_name(service_name: String) -> String {
     format!("{}-rsa-token", service_name)
 }
+
+/// Service name qualified by its namespace, unique across cluster
+pub fn qualified_name(namespace: Option<&str>, service_name: &str) -> String {
+    format!("{}.{}", namespace.unwrap_or("default"), service_name)
+}
+
+pub fn public_key_name(service_name: &str) -> String {
+    format!("{}.pem", service_name)
+}
+
+pub fn certificate_name(service_name: &str) -> String {
+    format!("{}.crt", service_name)
+}
+
+/// `_` is not allowed in names, so previous key never collides with key of another service
+pub fn previous_key_name(service_name: &str) -> String {
+    format!("{}_previous.pem", service_name)
+}
+
+/// Name of service entry `name` in public secret of `target` namespace
+///
+/// Service own namespace keeps plain name, copies in other namespaces are prefixed with
+/// `<namespace>__`, so same-named services of different namespaces don't overwrite each other
+pub fn published_name(namespace: Option<&str>, target: &str, name: &str) -> String {
+    match namespace.unwrap_or("default") {
+        namespace if namespace == target => name.to_string(),
+        namespace => format!("{}__{}", namespace, name),
+    }
+}
+
+/// Namespace and service of entry named by `published_name` in public secret of `target` namespace
+pub fn parse_entry_name<'a>(target: &'a str, name: &'a str) -> Option<(&'a str, &'a str)> {
+    let stem = name
+        .strip_suffix(".pem")
+        .or_else(|| name.strip_suffix(".crt"))?;
+    let (namespace, stem) = stem.split_once("__").unwrap_or((target, stem));
+    let service_name = stem.strip_suffix("_previous").unwrap_or(stem);
+    if is_valid_name(namespace) && !service_name.is_empty() && !service_name.contains('_') {
+        Some((namespace, service_name))
+    } else {
+        None
+    }
+}
+
+/// Check that `name` is a valid DNS-1123 label, so it fits secret names and keys
+pub fn is_valid_name(name: &str) -> bool {
+    name.len() <= 63
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && !name.is_empty()
+}
+
+/// Check that kube request failed because object is missing
+pub fn is_not_found(error: &kube::Error) -> bool {
+    match error {
+        kube::Error::Api(response) => response.code == 404,
+        _ => false,
+    }
+}
+
+/// Check that kube request failed because object already exists or was changed meanwhile
+pub fn is_conflict(error: &kube::Error) -> bool {
+    match error {
+        kube::Error::Api(response) => response.code == 409,
+        _ => false,
+    }
+}
+
+/// Check that `key` is a valid secret data key
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= 253
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Check that `path` is an absolute container path without `..` segments
+pub fn is_valid_mount_path(path: &str) -> bool {
+    path.starts_with('/') && !path.contains(':') && path.split('/').all(|segment| segment != "..")
+}
+
+/// Check that `path` is a relative file path inside volume without `..` segments
+pub fn is_valid_file_path(path: &str) -> bool {
+    !path.is_empty() && !path.starts_with('/') && path.split('/').all(|segment| segment != "..")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_error(code: u16) -> kube::Error {
+        kube::Error::Api(kube::ErrorResponse {
+            status: "Failure".into(),
+            message: String::new(),
+            reason: String::new(),
+            code,
+        })
+    }
+
+    #[test]
+    fn only_404_means_not_found() {
+        assert!(is_not_found(&api_error(404)));
+        assert!(!is_not_found(&api_error(403)));
+        assert!(!is_not_found(&api_error(500)));
+        assert!(is_conflict(&api_error(409)));
+        assert!(!is_conflict(&api_error(404)));
+        assert!(!is_not_found(&kube::Error::RequestValidation("bad".into())));
+    }
+
+    #[test]
+    fn entry_names_are_qualified_outside_own_namespace() {
+        assert_eq!(public_key_name("api"), "api.pem");
+        assert_eq!(certificate_name("api"), "api.crt");
+        assert_eq!(previous_key_name("api"), "api_previous.pem");
+        assert_eq!(
+            published_name(Some("shop"), "shop", &public_key_name("api")),
+            "api.pem"
+        );
+        assert_eq!(
+            published_name(None, "default", &public_key_name("api")),
+            "api.pem"
+        );
+        assert_eq!(
+            published_name(Some("shop"), "blog", &previous_key_name("api")),
+            "shop__api_previous.pem"
+        );
+    }
+
+    #[test]
+    fn entry_names_round_trip() {
+        for target in &["shop", "blog"] {
+            for name in &[
+                public_key_name("api"),
+                certificate_name("api"),
+                previous_key_name("api"),
+            ] {
+                let published = published_name(Some("shop"), target, name);
+                assert_eq!(parse_entry_name(target, &published), Some(("shop", "api")));
+            }
+        }
+        // Key pairs may have dots in their names
+        assert_eq!(
+            parse_entry_name("blog", &published_name(Some("shop"), "blog", "api.v2.pem")),
+            Some(("shop", "api.v2"))
+        );
+        for target in &["shop", "blog"] {
+            let published = published_name(Some("shop"), target, &public_key_name("previous"));
+            assert_eq!(
+                parse_entry_name(target, &published),
+                Some(("shop", "previous"))
+            );
+        }
+    }
+
+    #[test]
+    fn foreign_entries_are_not_parsed() {
+        assert_eq!(parse_entry_name("shop", "jwks.json"), None);
+        assert_eq!(parse_entry_name("shop", "Shop__api.pem"), None);
+        assert_eq!(parse_entry_name("shop", "my_key.pem"), None);
+        assert_eq!(parse_entry_name("shop", ".pem"), None);
+    }
+
+    #[test]
+    fn names_are_dns_labels() {
+        assert!(is_valid_name("api"));
+        assert!(is_valid_name("api-v2"));
+        assert!(is_valid_name(&"a".repeat(63)));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("-api"));
+        assert!(!is_valid_name("api-"));
+        assert!(!is_valid_name("Api"));
+        assert!(!is_valid_name("shop.api"));
+        assert!(!is_valid_name(&"a".repeat(64)));
+    }
+
+    #[test]
+    fn keys_are_secret_data_keys() {
+        assert!(is_valid_key("private.pem"));
+        assert!(is_valid_key("tls_key-1"));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key("keys/private.pem"));
+        assert!(!is_valid_key(&"a".repeat(254)));
+    }
+
+    #[test]
+    fn mount_paths_are_absolute() {
+        assert!(is_valid_mount_path("/etc/keys"));
+        assert!(is_valid_mount_path("/etc/..keys"));
+        assert!(!is_valid_mount_path("etc/keys"));
+        assert!(!is_valid_mount_path("/etc/../keys"));
+        assert!(!is_valid_mount_path("/etc/keys:ro"));
+    }
+
+    #[test]
+    fn file_paths_stay_inside_volume() {
+        assert!(is_valid_file_path("private.pem"));
+        assert!(is_valid_file_path("keys/private.pem"));
+        assert!(!is_valid_file_path(""));
+        assert!(!is_valid_file_path("/private.pem"));
+        assert!(!is_valid_file_path("../private.pem"));
+        assert!(!is_valid_file_path("keys/../../private.pem"));
+    }
+}
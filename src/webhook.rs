@@ -0,0 +1,34 @@
+use crate::state::Controller;
+use base64::encode;
+use serde_json::{json, Value};
+
+/// Answer AdmissionReview request, pods are always admitted
+///
+/// Volumes are injected with JSON patch when pod belongs to managed workload
+pub async fn review(controller: &Controller, review: Value) -> Value {
+    let request = &review["request"];
+    let uid = request["uid"].clone();
+    let mut response = json!({
+        "uid": uid,
+        "allowed": true,
+    });
+
+    if request["kind"]["kind"] == "Pod" && request["operation"] == "CREATE" {
+        let namespace = request["namespace"].as_str().unwrap_or("default");
+        match controller.mutate_pod(namespace, &request["object"]).await {
+            Ok(Some(patch)) => {
+                response["patchType"] = "JSONPatch".into();
+                response["patch"] = encode(&patch.to_string()).into();
+            }
+            Ok(None) => {}
+            // Operator failures must not block pod creation
+            Err(e) => warn!("Cannot mutate pod in namespace {}: {}", namespace, e),
+        }
+    }
+
+    json!({
+        "apiVersion": review["apiVersion"].as_str().unwrap_or("admission.k8s.io/v1"),
+        "kind": "AdmissionReview",
+        "response": response,
+    })
+}
@@ -0,0 +1,381 @@
+use anyhow::Result;
+use futures::Future;
+use k8s_openapi::serde_json;
+use kube::{
+    api::{Api, Object, OwnerReference},
+    client::APIClient,
+};
+use serde_json::{json, Value};
+use std::fmt;
+
+/// Annotated object with pod template, spec layout depends on its kind
+pub type Workload = Object<Value, Value>;
+
+/// Supported workload kinds
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Deployment,
+    StatefulSet,
+    DaemonSet,
+    Job,
+    CronJob,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Kind::Deployment => "Deployment",
+            Kind::StatefulSet => "StatefulSet",
+            Kind::DaemonSet => "DaemonSet",
+            Kind::Job => "Job",
+            Kind::CronJob => "CronJob",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Kind {
+    /// All supported kinds
+    pub fn all() -> Vec<Kind> {
+        vec![
+            Kind::Deployment,
+            Kind::StatefulSet,
+            Kind::DaemonSet,
+            Kind::Job,
+            Kind::CronJob,
+        ]
+    }
+
+    /// Group, version and plural name of resource
+    fn resource(self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            Kind::Deployment => ("apps", "v1", "deployments"),
+            Kind::StatefulSet => ("apps", "v1", "statefulsets"),
+            Kind::DaemonSet => ("apps", "v1", "daemonsets"),
+            Kind::Job => ("batch", "v1", "jobs"),
+            Kind::CronJob => ("batch", "v1beta1", "cronjobs"),
+        }
+    }
+
+    /// Workload api for all namespaces
+    pub fn api(self, client: APIClient) -> Api<Workload> {
+        let (group, version, plural) = self.resource();
+        Api::customResource(client, plural)
+            .group(group)
+            .version(version)
+    }
+
+    /// Kind of owner reference
+    pub fn from_owner(owner: &OwnerReference) -> Option<Kind> {
+        Kind::all().into_iter().find(|kind| {
+            let (group, _, _) = kind.resource();
+            kind.to_string() == owner.kind && owner.apiVersion.starts_with(group)
+        })
+    }
+
+    /// Reference to workload for objects owned by it
+    pub fn owner_reference(self, workload: &Workload) -> Option<OwnerReference> {
+        let (group, version, _) = self.resource();
+        Some(OwnerReference {
+            controller: false,
+            blockOwnerDeletion: false,
+            name: workload.metadata.name.clone(),
+            apiVersion: format!("{}/{}", group, version),
+            kind: self.to_string(),
+            uid: workload.metadata.uid.clone()?,
+        })
+    }
+
+    /// Job pod template can't be changed after creation
+    pub fn is_template_mutable(self) -> bool {
+        self != Kind::Job
+    }
+
+    /// Pod spec of workload template
+    ///
+    /// It's kept as raw JSON, so fields unknown to k8s-openapi survive patching
+    pub fn pod_spec(self, workload: &Workload) -> Result<&Value> {
+        let pointer = match self {
+            Kind::CronJob => "/jobTemplate/spec/template/spec",
+            _ => "/template/spec",
+        };
+        workload.spec.pointer(pointer).ok_or_else(|| {
+            anyhow::format_err!("Missing pod spec for {} '{}'", self, workload.metadata.name)
+        })
+    }
+
+    /// Wrap pod spec patch into workload patch
+    pub fn template_patch(self, pod_spec: Value) -> Value {
+        let template = json!({
+            "template": {
+                "spec": pod_spec,
+            },
+        });
+        match self {
+            Kind::CronJob => json!({
+                "spec": {
+                    "jobTemplate": {
+                        "spec": template,
+                    },
+                },
+            }),
+            _ => json!({ "spec": template }),
+        }
+    }
+}
+
+/// ReplicaSet api for all namespaces, it links Deployment with its pods
+pub fn replica_sets(client: APIClient) -> Api<Workload> {
+    Api::customResource(client, "replicasets")
+        .group("apps")
+        .version("v1")
+}
+
+/// Follow controller owners of pod up to the first workload accepted by `is_managed`
+///
+/// Pod -> ReplicaSet -> Deployment or Pod -> Job -> CronJob, only `watched` kinds are followed.
+/// `get` fetches owner by name, `None` kind stands for ReplicaSet.
+pub async fn pod_owner<F, Fut>(
+    mut owners: Vec<OwnerReference>,
+    watched: &[Kind],
+    is_managed: impl Fn(Kind, &Workload) -> bool,
+    get: F,
+) -> Result<Option<(Kind, Workload)>>
+where
+    F: Fn(Option<Kind>, String) -> Fut,
+    Fut: Future<Output = Result<Workload>>,
+{
+    for _ in 0..2 {
+        let owner = match owners.into_iter().find(|owner| owner.controller) {
+            Some(owner) => owner,
+            None => return Ok(None),
+        };
+        let kind = match Kind::from_owner(&owner) {
+            Some(kind) if watched.contains(&kind) => Some(kind),
+            None if owner.kind == "ReplicaSet" => None,
+            _ => return Ok(None),
+        };
+        let workload = get(kind, owner.name).await?;
+
+        if let Some(kind) = kind {
+            if is_managed(kind, &workload) {
+                return Ok(Some((kind, workload)));
+            }
+        }
+        owners = workload.metadata.ownerReferences;
+    }
+    Ok(None)
+}
+
+/// Check that workload is a Job spawned by CronJob, it's served through the CronJob
+///
+/// Workloads controlled by other controllers, e.g. Jobs of third party operators, are handled as is
+pub fn is_spawned(kind: Kind, workload: &Workload) -> bool {
+    kind == Kind::Job
+        && workload
+            .metadata
+            .ownerReferences
+            .iter()
+            .any(|owner| owner.controller && owner.kind == Kind::CronJob.to_string())
+}
+
+/// Owner of another kind with the same name as `workload`, which keys named after it belong to
+pub fn conflicting_owner<'a>(
+    kind: Kind,
+    workload: &Workload,
+    owners: &'a [OwnerReference],
+) -> Option<&'a OwnerReference> {
+    owners
+        .iter()
+        .find(|owner| owner.name == workload.metadata.name && owner.kind != kind.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::{cell::RefCell, collections::BTreeMap};
+
+    fn owner(kind: &str, api_version: &str, name: &str) -> OwnerReference {
+        OwnerReference {
+            controller: true,
+            blockOwnerDeletion: true,
+            name: name.into(),
+            apiVersion: api_version.into(),
+            kind: kind.into(),
+            uid: format!("{}-uid", name),
+        }
+    }
+
+    fn workload(name: &str, owners: Vec<OwnerReference>, managed: bool) -> Workload {
+        let annotations: BTreeMap<String, String> = if managed {
+            vec![("rsa.customer.keys/service".into(), "true".into())]
+                .into_iter()
+                .collect()
+        } else {
+            BTreeMap::new()
+        };
+        serde_json::from_value(json!({
+            "metadata": {
+                "name": name,
+                "annotations": annotations,
+                "ownerReferences": owners,
+            },
+            "spec": {},
+        }))
+        .unwrap()
+    }
+
+    /// Fetched owner kind and name, `None` kind is ReplicaSet
+    type Fetch = (Option<Kind>, String);
+
+    /// Resolve pod owners against objects keyed by name, returns owner and fetched objects
+    fn resolve(
+        owners: Vec<OwnerReference>,
+        watched: &[Kind],
+        objects: Vec<Workload>,
+    ) -> (Option<(Kind, String)>, Vec<Fetch>) {
+        let fetched = RefCell::new(vec![]);
+        let owner = block_on(pod_owner(
+            owners,
+            watched,
+            |_, workload| {
+                workload
+                    .metadata
+                    .annotations
+                    .contains_key("rsa.customer.keys/service")
+            },
+            |kind, name| {
+                fetched.borrow_mut().push((kind, name.clone()));
+                let object = objects.iter().find(|o| o.metadata.name == name).cloned();
+                async move { object.ok_or_else(|| anyhow::format_err!("{} not found", name)) }
+            },
+        ))
+        .unwrap();
+        (
+            owner.map(|(kind, workload)| (kind, workload.metadata.name)),
+            fetched.into_inner(),
+        )
+    }
+
+    #[test]
+    fn follows_replica_set_to_deployment() {
+        let objects = vec![
+            workload(
+                "api-7d9f",
+                vec![owner("Deployment", "apps/v1", "api")],
+                false,
+            ),
+            workload("api", vec![], true),
+        ];
+        let (owner, fetched) = resolve(
+            vec![owner("ReplicaSet", "apps/v1", "api-7d9f")],
+            &Kind::all(),
+            objects,
+        );
+        assert_eq!(owner, Some((Kind::Deployment, "api".into())));
+        assert_eq!(
+            fetched,
+            vec![
+                (None, "api-7d9f".to_string()),
+                (Some(Kind::Deployment), "api".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn follows_job_to_cron_job() {
+        let objects = vec![
+            workload(
+                "backup-123",
+                vec![owner("CronJob", "batch/v1beta1", "backup")],
+                false,
+            ),
+            workload("backup", vec![], true),
+        ];
+        let (owner, _) = resolve(
+            vec![owner("Job", "batch/v1", "backup-123")],
+            &Kind::all(),
+            objects,
+        );
+        assert_eq!(owner, Some((Kind::CronJob, "backup".into())));
+    }
+
+    #[test]
+    fn skips_unwatched_and_unmanaged_owners() {
+        let objects = vec![
+            workload(
+                "api-7d9f",
+                vec![owner("Deployment", "apps/v1", "api")],
+                false,
+            ),
+            workload("api", vec![], false),
+        ];
+        let owners = vec![owner("ReplicaSet", "apps/v1", "api-7d9f")];
+
+        let (owner, fetched) = resolve(owners.clone(), &[Kind::StatefulSet], objects.clone());
+        assert_eq!(owner, None);
+        assert_eq!(fetched, vec![(None, "api-7d9f".to_string())]);
+
+        let (owner, _) = resolve(owners, &Kind::all(), objects);
+        assert_eq!(owner, None);
+    }
+
+    #[test]
+    fn ignores_pods_without_controller() {
+        let mut reference = owner("ReplicaSet", "apps/v1", "api-7d9f");
+        reference.controller = false;
+        let (owner, fetched) = resolve(vec![reference], &Kind::all(), vec![]);
+        assert_eq!(owner, None);
+        assert!(fetched.is_empty());
+    }
+
+    #[test]
+    fn only_cron_job_runs_are_spawned() {
+        let run = workload(
+            "backup-28000",
+            vec![owner("CronJob", "batch/v1beta1", "backup")],
+            true,
+        );
+        assert!(is_spawned(Kind::Job, &run));
+
+        let foreign = workload(
+            "migrate",
+            vec![owner("Workflow", "argoproj.io/v1alpha1", "etl")],
+            true,
+        );
+        assert!(!is_spawned(Kind::Job, &foreign));
+
+        let rollout = workload(
+            "api",
+            vec![owner("Rollout", "argoproj.io/v1alpha1", "api")],
+            true,
+        );
+        assert!(!is_spawned(Kind::Deployment, &rollout));
+    }
+
+    #[test]
+    fn same_name_of_another_kind_conflicts() {
+        let api = workload("api", vec![], true);
+        let deployment = owner("Deployment", "apps/v1", "api");
+        let canary = owner("Deployment", "apps/v1", "api-canary");
+        let stateful_set = owner("StatefulSet", "apps/v1", "api");
+        let conflict = |kind, owners: &[OwnerReference]| {
+            conflicting_owner(kind, &api, owners).map(|owner| owner.kind.clone())
+        };
+
+        assert_eq!(
+            conflict(Kind::Deployment, &[deployment.clone(), canary.clone()]),
+            None
+        );
+        assert_eq!(
+            conflict(Kind::Deployment, &[canary, stateful_set]),
+            Some("StatefulSet".to_string())
+        );
+        assert_eq!(
+            conflict(Kind::StatefulSet, &[deployment]),
+            Some("Deployment".to_string())
+        );
+    }
+}